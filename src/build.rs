@@ -0,0 +1,56 @@
+//! Helpers for build scripts.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Writes the list of migration files in `dir` for [`embed_migrations!`](crate::embed_migrations)
+/// to include. `dir` is relative to the crate's manifest, and the build script reruns whenever
+/// a file in it changes.
+pub fn embed_migrations(dir: impl AsRef<Path>) -> io::Result<()> {
+    let dir = dir.as_ref();
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").map_err(to_io_error)?;
+    let out_dir = env::var("OUT_DIR").map_err(to_io_error)?;
+
+    let source_dir = Path::new(&manifest_dir).join(dir);
+    println!("cargo:rerun-if-changed={}", source_dir.display());
+
+    let mut files: Vec<(String, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(&source_dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+            if !name.starts_with('.') {
+                files.push((name.to_string(), path.clone()));
+            }
+        }
+    }
+    files.sort();
+
+    let mut code = String::from("&[\n");
+    for (name, path) in files {
+        let path = path.to_str().ok_or_else(|| {
+            to_io_error(format!("migration path {} is not utf-8", path.display()))
+        })?;
+        code.push_str(&format!(
+            "    ::migratour::EmbeddedMigration {{ file_name: {:?}, sql: include_str!({:?}) }},\n",
+            name, path
+        ));
+    }
+    code.push(']');
+
+    let out_file = Path::new(&out_dir)
+        .join("migratour")
+        .join(format!("{}.rs", dir.display()));
+    if let Some(parent) = out_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(out_file, code)
+}
+
+fn to_io_error(e: impl ToString) -> io::Error {
+    io::Error::other(e.to_string())
+}
@@ -1,548 +1,1026 @@
 use async_trait::async_trait;
 // use futures::stream::StreamExt;
-use sqlx::{Database, MySql, Pool, Postgres, Row};
-
-use std::{error::Error, fs};
-
+use sqlx::database::HasArguments;
+use sqlx::pool::PoolConnection;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqliteLockingMode};
+use sqlx::{
+    ColumnIndex, ConnectOptions, Connection, Database, Decode, Encode, Executor, IntoArguments,
+    MySql, Pool, Postgres, Row, Sqlite, Type,
+};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+use std::cmp::Reverse;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::dialect::{
+    Dialect, MigrationTable, MySqlDialect, PostgresDialect, SqliteDialect, UNVERSIONED_LAYOUTS,
+};
+use crate::error::{MigratourError, Result};
+use crate::migration::{
+    checksum, migration_statements, AppliedMigration, HistoryEntry, Migration, RunInfo,
+};
+use crate::DatabaseType;
+
+// what a backend adds to the shared SqlDb: connecting and the migration lock. the sql it
+// needs comes from its dialect
 #[async_trait]
-pub trait Db {
+pub trait Db: Sized {
     type A: Database;
-    type B: Db;
 
-    async fn new_connection(database_url: String) -> Result<Self::B, Box<dyn Error>>;
+    // run is recorded with every migration applied or reverted through the connection
+    fn from_pool(pool: Pool<Self::A>, table: MigrationTable, run: RunInfo) -> Self;
 
-    async fn ping_db(&self) -> Result<(), Box<dyn Error>>;
+    async fn new_connection(
+        database_url: String,
+        table: MigrationTable,
+        run: RunInfo,
+    ) -> Result<Self>;
 
-    async fn table_exists(&self) -> Result<bool, Box<dyn Error>>;
+    async fn acquire_lock(&self, timeout: Duration) -> Result<()>;
 
-    async fn create_migration_table(&self) -> Result<(), Box<dyn Error>>;
+    async fn release_lock(&self) -> Result<()>;
+}
 
-    async fn get_migration_table_count(&self) -> Result<usize, Box<dyn Error>>;
+// lock names are limited to 64 characters, so the table name is hashed
+fn mysql_lock_name(table: &MigrationTable) -> String {
+    format!("migratour_{}", hex::encode(table.lock_key()))
+}
 
-    async fn get_last_migration(&self) -> Result<String, Box<dyn Error>>;
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+// runs the up or down sql of a migration statement by statement. statements are sent without
+// bind parameters so that they go over the simple query protocol, which also accepts
+// statements mysql refuses to prepare (triggers, routines)
+async fn execute_migration<DB: Database>(
+    migration: &Migration,
+    direction: &'static str,
+    database: &DatabaseType,
+    conn: &mut DB::Connection,
+) -> Result<Duration>
+where
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+{
+    let (file, statements) = migration_statements(migration, direction, database)?;
+    let started = Instant::now();
+
+    let total = statements.len();
+    for (i, statement) in statements.iter().enumerate() {
+        if let Err(e) = (&mut *conn).execute(statement.sql.as_str()).await {
+            return Err(MigratourError::StatementFailed {
+                migration: migration.name.clone(),
+                file: file.clone(),
+                index: i + 1,
+                total,
+                line: statement.line,
+                source: e,
+            });
+        }
+    }
 
-    async fn insert_migration(
-        &self,
-        name: &String,
-        tx: &mut sqlx::Transaction<'_, Self::A>,
-    ) -> Result<(), Box<dyn Error>>;
+    Ok(started.elapsed())
+}
 
-    async fn apply_migration(
-        &self,
-        migration_query: &String,
-        tx: &mut sqlx::Transaction<'_, Self::A>,
-    ) -> Result<(), Box<dyn Error>>;
+// the run metadata of a row, stored in the "<prefix>_by", "<prefix>_host", ... columns. rows
+// recorded before it was have none
+fn run_info<R: Row>(row: &R, prefix: &str) -> Result<Option<RunInfo>>
+where
+    for<'r> String: Decode<'r, R::Database> + Type<R::Database>,
+    for<'a> &'a str: ColumnIndex<R>,
+{
+    let user: Option<String> = row.try_get(format!("{}_by", prefix).as_str())?;
+    let host: Option<String> = row.try_get(format!("{}_host", prefix).as_str())?;
+    let version: Option<String> = row.try_get(format!("{}_migratour_version", prefix).as_str())?;
+    let environment: Option<String> = row.try_get(format!("{}_environment", prefix).as_str())?;
+
+    Ok(user.map(|user| RunInfo {
+        user,
+        host: host.unwrap_or_default(),
+        migratour_version: version.unwrap_or_default(),
+        environment,
+    }))
+}
 
-    async fn delete_migration(
-        &self,
-        name: &String,
-        tx: &mut sqlx::Transaction<'_, Self::A>,
-    ) -> Result<(), Box<dyn Error>>;
+fn duration_ms(duration: Duration) -> i64 {
+    duration.as_millis().try_into().unwrap_or(i64::MAX)
+}
 
-    async fn revert_migration(
-        &self,
-        migration_query: &String,
-        tx: &mut sqlx::Transaction<'_, Self::A>,
-    ) -> Result<(), Box<dyn Error>>;
+fn from_ms(ms: Option<i64>) -> Option<u64> {
+    ms.and_then(|ms| ms.try_into().ok())
+}
 
-    async fn up_migration_transaction(
-        &self,
-        unapplied_migrations: Vec<&String>,
-        migrations_to_apply: i32,
-    ) -> Result<(), Box<dyn Error>>;
+fn seq(seq: Option<i64>) -> Option<u64> {
+    seq.and_then(|seq| seq.try_into().ok())
+}
 
-    async fn down_migration_transaction(
-        &self,
-        down_migrations: Vec<&String>,
-    ) -> Result<(), Box<dyn Error>>;
+// the held migration lock
+enum Lock<DB: Database> {
+    // postgres and mysql lock the session of a connection
+    Session(PoolConnection<DB>),
+    // an exclusive lock on a file next to the sqlite database, through a connection of its own
+    File(SqliteConnection),
 }
 
-pub struct PostgresDb {
-    pub pool: Pool<Postgres>,
+// a connection to run on: the one holding the migration lock while it is held, so that a pool
+// of one connection is enough, otherwise one from the pool
+enum Conn<'a, DB: Database> {
+    Locked(MappedMutexGuard<'a, PoolConnection<DB>>),
+    Pooled(PoolConnection<DB>),
 }
 
-#[async_trait]
-impl Db for PostgresDb {
-    type A = Postgres;
-    type B = PostgresDb;
-    async fn new_connection(database_url: String) -> Result<PostgresDb, Box<dyn Error>> {
-        let pool = sqlx::postgres::PgPool::connect(&database_url).await?;
+impl<DB: Database> Deref for Conn<'_, DB> {
+    type Target = DB::Connection;
 
-        return Ok(PostgresDb { pool });
+    fn deref(&self) -> &DB::Connection {
+        match self {
+            Conn::Locked(conn) => conn,
+            Conn::Pooled(conn) => conn,
+        }
     }
+}
 
-    async fn ping_db(&self) -> Result<(), Box<dyn Error>> {
-        let result = sqlx::query("SELECT 1 + 1 as sum")
-            .fetch_one(&self.pool)
+impl<DB: Database> DerefMut for Conn<'_, DB> {
+    fn deref_mut(&mut self) -> &mut DB::Connection {
+        match self {
+            Conn::Locked(conn) => conn,
+            Conn::Pooled(conn) => conn,
+        }
+    }
+}
+
+// the migrations table of one database. the backends share everything but connecting and
+// locking, the sql differing between databases comes from their dialect
+pub struct SqlDb<DB: Database> {
+    pub pool: Pool<DB>,
+    database: DatabaseType,
+    dialect: &'static dyn Dialect,
+    table: MigrationTable,
+    run: RunInfo,
+    lock: Mutex<Option<Lock<DB>>>,
+}
+
+pub type PostgresDb = SqlDb<Postgres>;
+pub type MySqlDb = SqlDb<MySql>;
+pub type SqliteDb = SqlDb<Sqlite>;
+
+impl<DB> SqlDb<DB>
+where
+    DB: Database,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> <DB as HasArguments<'q>>::Arguments: IntoArguments<'q, DB>,
+    for<'q> &'q str: Encode<'q, DB> + Type<DB>,
+    for<'q> Option<&'q str>: Encode<'q, DB>,
+    for<'q> Option<i64>: Encode<'q, DB>,
+    for<'q> bool: Encode<'q, DB> + Decode<'q, DB> + Type<DB>,
+    for<'q> i64: Encode<'q, DB> + Decode<'q, DB> + Type<DB>,
+    for<'r> i32: Decode<'r, DB> + Type<DB>,
+    for<'r> String: Decode<'r, DB> + Type<DB>,
+    for<'a> &'a str: ColumnIndex<DB::Row>,
+{
+    fn new(
+        pool: Pool<DB>,
+        database: DatabaseType,
+        dialect: &'static dyn Dialect,
+        table: MigrationTable,
+        run: RunInfo,
+    ) -> SqlDb<DB> {
+        SqlDb {
+            pool,
+            database,
+            dialect,
+            table,
+            run,
+            lock: Mutex::new(None),
+        }
+    }
+
+    async fn acquire(&self) -> Result<Conn<'_, DB>> {
+        let locked = MutexGuard::try_map(self.lock.lock().await, |lock| match lock {
+            Some(Lock::Session(conn)) => Some(conn),
+            _ => None,
+        })
+        .ok();
+
+        match locked {
+            Some(conn) => Ok(Conn::Locked(conn)),
+            None => Ok(Conn::Pooled(self.pool.acquire().await?)),
+        }
+    }
+
+    async fn exists(&self, table: &MigrationTable) -> Result<bool> {
+        let mut conn = self.acquire().await?;
+        let result = sqlx::query(&self.dialect.table_exists(table))
+            .fetch_one(&mut *conn)
             .await?;
+        let count: i64 = result.try_get("count")?;
 
-        let _s: i32 = result.get("sum");
+        Ok(count > 0)
+    }
+
+    pub async fn ping_db(&self) -> Result<()> {
+        let mut conn = self.acquire().await?;
+        sqlx::query("SELECT 1 + 1 as sum")
+            .fetch_one(&mut *conn)
+            .await?;
 
         Ok(())
     }
 
-    async fn table_exists(&self) -> Result<bool, Box<dyn Error>> {
-        let table_exits_sql = "SELECT EXISTS (
-            SELECT FROM information_schema.tables 
-            where  table_name = 'db_migrations'
-            );";
+    pub async fn table_exists(&self) -> Result<bool> {
+        self.exists(&self.table).await
+    }
 
-        let results = sqlx::query(table_exits_sql).fetch_one(&self.pool).await?;
-        let tb_exists: bool = results.get("exists");
+    pub async fn create_migration_table(&self) -> Result<()> {
+        let mut conn = self.acquire().await?;
 
-        Ok(tb_exists)
+        if let Some(sql) = self
+            .table
+            .schema
+            .as_deref()
+            .and_then(|schema| self.dialect.create_schema(schema))
+        {
+            sqlx::query(&sql).execute(&mut *conn).await?;
+        }
+
+        let mut tx = conn.begin().await?;
+
+        sqlx::query(&self.dialect.create_migration_table(&self.table.qualified()))
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(&self.dialect.create_name_index(&self.table))
+            .execute(&mut *tx)
+            .await?;
+        for sql in self.dialect.set_table_version(&self.table) {
+            sqlx::query(&sql).execute(&mut *tx).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
     }
 
-    async fn create_migration_table(&self) -> Result<(), Box<dyn Error>> {
-        let create_table_sql = "create table db_migrations(
-            id serial primary key,
-            name text unique,
-            valid bool ,
-            created_at timestamp not null DEFAULT now(),
-            deleted_at timestamp
-        );";
+    // layout version of an existing migrations table
+    pub async fn migration_table_version(&self) -> Result<i32> {
+        let meta_exists = self.exists(&self.table.meta()).await?;
+        let mut conn = self.acquire().await?;
 
-        sqlx::query(create_table_sql).execute(&self.pool).await?;
+        if meta_exists {
+            let result = sqlx::query(&self.dialect.table_version(&self.table))
+                .fetch_one(&mut *conn)
+                .await?;
+
+            return Ok(result.try_get("version")?);
+        }
+
+        for (column, version) in UNVERSIONED_LAYOUTS {
+            let result = sqlx::query(&self.dialect.column_exists(&self.table, column))
+                .fetch_one(&mut *conn)
+                .await?;
+            let count: i64 = result.try_get("count")?;
+            if count > 0 {
+                return Ok(*version);
+            }
+        }
+
+        Ok(1)
+    }
+
+    pub async fn upgrade_migration_table(&self, from: i32) -> Result<()> {
+        let mut conn = self.acquire().await?;
+        let mut tx = conn.begin().await?;
+
+        for sql in self.dialect.upgrade_migration_table(&self.table, from) {
+            sqlx::query(&sql).execute(&mut *tx).await?;
+        }
+        if from < 6 {
+            self.number_events(&mut tx).await?;
+        }
+
+        tx.commit().await?;
 
         Ok(())
     }
 
-    async fn get_migration_table_count(&self) -> Result<usize, Box<dyn Error>> {
-        let result = sqlx::query("SELECT id from db_migrations")
-            .fetch_all(&self.pool)
+    pub async fn get_migration_table_count(&self) -> Result<usize> {
+        let mut conn = self.acquire().await?;
+        let result = sqlx::query(&self.dialect.migration_count(&self.table.qualified()))
+            .fetch_one(&mut *conn)
             .await?;
-        let count = result.len();
+        let count: i64 = result.try_get("count")?;
 
-        Ok(count)
+        Ok(count as usize)
     }
 
-    async fn get_last_migration(&self) -> Result<String, Box<dyn Error>> {
-        let result = sqlx::query("Select name from db_migrations order by id desc limit 1;")
-            .fetch_one(&self.pool)
+    // none when no migration is applied
+    pub async fn get_last_migration(&self) -> Result<Option<String>> {
+        let mut conn = self.acquire().await?;
+        let result = sqlx::query(&self.dialect.last_migration(&self.table.qualified()))
+            .fetch_optional(&mut *conn)
             .await?;
 
-        let name = result.try_get("name")?;
+        match result {
+            Some(row) => Ok(Some(row.try_get("name")?)),
+            None => Ok(None),
+        }
+    }
 
-        Ok(name)
+    pub async fn get_applied_migrations(&self) -> Result<Vec<AppliedMigration>> {
+        let mut conn = self.acquire().await?;
+        let result = sqlx::query(&self.dialect.applied_migrations(&self.table.qualified()))
+            .fetch_all(&mut *conn)
+            .await?;
+
+        let mut applied = Vec::with_capacity(result.len());
+        for row in result {
+            applied.push(AppliedMigration {
+                name: row.try_get("name")?,
+                applied_at: row.try_get("applied_at")?,
+                checksum: row.try_get("checksum")?,
+                dirty: row.try_get("dirty")?,
+                applied_by: run_info(&row, "applied")?,
+                duration_ms: from_ms(row.try_get("apply_duration_ms")?),
+            });
+        }
+
+        Ok(applied)
     }
 
-    // async fn new_transaction(&self) -> Result<sqlx::Transaction<'_, Postgres>, Box<dyn Error>> {
-    //     let tx: sqlx::Transaction<'_, Postgres> = self.pool.begin().await?;
-    //     Ok(tx)
-    // }
+    pub async fn get_migration_history(&self) -> Result<Vec<HistoryEntry>> {
+        let mut conn = self.acquire().await?;
+        let result = sqlx::query(&self.dialect.migration_history(&self.table.qualified()))
+            .fetch_all(&mut *conn)
+            .await?;
+
+        let mut history = Vec::with_capacity(result.len());
+        for row in result {
+            history.push(HistoryEntry {
+                version: None,
+                version_label: None,
+                name: row.try_get("name")?,
+                checksum: row.try_get("checksum")?,
+                dirty: row.try_get("dirty")?,
+                applied_at: row.try_get("applied_at")?,
+                applied_by: run_info(&row, "applied")?,
+                apply_duration_ms: from_ms(row.try_get("apply_duration_ms")?),
+                applied_seq: seq(row.try_get("applied_seq")?).unwrap_or_default(),
+                reverted_at: row.try_get("reverted_at")?,
+                reverted_by: run_info(&row, "reverted")?,
+                revert_duration_ms: from_ms(row.try_get("revert_duration_ms")?),
+                reverted_seq: seq(row.try_get("reverted_seq")?),
+                discarded: row.try_get("discarded")?,
+            });
+        }
+
+        Ok(history)
+    }
+
+    // number of the next apply or revert, ordering the history of the table
+    async fn next_seq(&self, conn: &mut DB::Connection) -> Result<i64> {
+        let result = sqlx::query(&self.dialect.last_seq(&self.table.qualified()))
+            .fetch_one(&mut *conn)
+            .await?;
+        let applied: i64 = result.try_get("applied")?;
+        let reverted: i64 = result.try_get("reverted")?;
+
+        Ok(applied.max(reverted) + 1)
+    }
+
+    // numbers the applies and reverts recorded before they were, in the order of their
+    // timestamps. within the same timestamp a revert goes right before the next apply of the
+    // same migration, and reverts not followed by one go last, newest migration first
+    async fn number_events(&self, conn: &mut DB::Connection) -> Result<()> {
+        let table = self.table.qualified();
+        let rows = sqlx::query(&self.dialect.unnumbered_events(&table))
+            .fetch_all(&mut *conn)
+            .await?;
+
+        let mut names = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let name: String = row.try_get("name")?;
+            names.push(name);
+        }
+
+        let mut events = Vec::with_capacity(rows.len() * 2);
+        for (i, row) in rows.iter().enumerate() {
+            let applied_at: String = row.try_get("applied_at")?;
+            let reverted_at: Option<String> = row.try_get("reverted_at")?;
+            events.push((applied_at, i, true, Reverse(i), false, i));
+            if let Some(reverted_at) = reverted_at {
+                let reapplied = (i + 1..rows.len())
+                    .find(|j| names[*j] == names[i])
+                    .unwrap_or(usize::MAX);
+                events.push((reverted_at, reapplied, false, Reverse(i), true, i));
+            }
+        }
+        events.sort();
+
+        let mut seqs: Vec<(Option<i64>, Option<i64>)> = vec![(None, None); rows.len()];
+        for (seq, (_, _, _, _, reverted, i)) in events.into_iter().enumerate() {
+            let seq = Some(seq as i64 + 1);
+            if reverted {
+                seqs[i].1 = seq;
+            } else {
+                seqs[i].0 = seq;
+            }
+        }
+
+        for (row, (applied_seq, reverted_seq)) in rows.iter().zip(seqs) {
+            let id: String = row.try_get("row_id")?;
+            let id: i64 = id.parse().map_err(|_| {
+                MigratourError::Config(format!("invalid id {} in the migrations table", id))
+            })?;
+            sqlx::query(&self.dialect.set_event_seq(&table))
+                .bind(applied_seq)
+                .bind(reverted_seq)
+                .bind(id)
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        Ok(())
+    }
 
     async fn insert_migration(
         &self,
-        name: &String,
-        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> Result<(), Box<dyn Error>> {
-        sqlx::query("INSERT INTO db_migrations(name, valid) VALUES ($1, $2);")
-            .bind(&name)
+        name: &str,
+        checksum: &str,
+        dirty: bool,
+        conn: &mut DB::Connection,
+    ) -> Result<()> {
+        let seq = self.next_seq(conn).await?;
+        sqlx::query(&self.dialect.insert_migration(&self.table.qualified()))
+            .bind(name)
             .bind(true)
-            .execute(&mut **tx)
+            .bind(checksum)
+            .bind(dirty)
+            .bind(self.run.user.as_str())
+            .bind(self.run.host.as_str())
+            .bind(self.run.migratour_version.as_str())
+            .bind(self.run.environment.as_deref())
+            .bind(seq)
+            .execute(&mut *conn)
             .await?;
 
         Ok(())
     }
 
-    async fn apply_migration(
-        &self,
-        migration_query: &String,
-        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> Result<(), Box<dyn Error>> {
-        let queries: Vec<&str> = migration_query.split(';').collect();
-
-        for query in queries {
-            sqlx::query(query).execute(&mut **tx).await?;
-        }
+    // a dirty migration started running but isn't known to have finished, its statements may
+    // have been partly applied
+    async fn set_dirty(&self, name: &str, dirty: bool, conn: &mut DB::Connection) -> Result<()> {
+        sqlx::query(&self.dialect.set_dirty(&self.table.qualified()))
+            .bind(dirty)
+            .bind(name)
+            .execute(&mut *conn)
+            .await?;
 
         Ok(())
     }
 
-    async fn delete_migration(
+    // marks a migration as clean and records how long its statements took
+    async fn finish_migration(
         &self,
-        name: &String,
-        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> Result<(), Box<dyn Error>> {
-        sqlx::query("DELETE from db_migrations where name = $1;")
+        name: &str,
+        duration: Duration,
+        conn: &mut DB::Connection,
+    ) -> Result<()> {
+        sqlx::query(&self.dialect.finish_migration(&self.table.qualified()))
+            .bind(duration_ms(duration))
             .bind(name)
-            .execute(&mut **tx)
+            .execute(&mut *conn)
             .await?;
 
         Ok(())
     }
 
-    async fn revert_migration(
+    // duration is how long the down statements took, none when a dirty migration is discarded
+    // without running them
+    async fn delete_migration(
         &self,
-        migration_query: &String,
-        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    ) -> Result<(), Box<dyn Error>> {
-        let queries: Vec<&str> = migration_query.split(';').collect();
+        name: &str,
+        duration: Option<Duration>,
+        conn: &mut DB::Connection,
+    ) -> Result<()> {
+        let seq = self.next_seq(conn).await?;
+        sqlx::query(&self.dialect.delete_migration(&self.table.qualified()))
+            .bind(self.run.user.as_str())
+            .bind(self.run.host.as_str())
+            .bind(self.run.migratour_version.as_str())
+            .bind(self.run.environment.as_deref())
+            .bind(duration.map(duration_ms))
+            .bind(seq)
+            .bind(duration.is_none())
+            .bind(name)
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(())
+    }
 
-        for query in queries {
-            sqlx::query(query).execute(&mut **tx).await?;
+    // a second connection from the pool, for dialects whose ddl commits implicitly. the
+    // migrations are recorded through it outside of their transaction: as dirty before their
+    // statements run, so that they stay dirty when the transaction fails halfway, and as done
+    // once it committed. the transaction itself never touches the migrations table, as the
+    // row locks of an update there would block the second connection until they time out
+    async fn outside_connection(&self) -> Result<Option<PoolConnection<DB>>> {
+        if self.dialect.transactional_ddl() {
+            return Ok(None);
         }
 
-        Ok(())
+        Ok(Some(self.pool.acquire().await?))
     }
 
-    async fn up_migration_transaction(
+    // with dry_run the migrations run inside the transaction, which is then rolled back
+    pub async fn up_migration_transaction(
         &self,
-        unapplied_migrations: Vec<&String>,
-        migrations_to_apply: i32,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut tx: sqlx::Transaction<'_, Postgres> = self.pool.begin().await?;
-
-        for i in 0..migrations_to_apply {
-            let mg = unapplied_migrations[i as usize];
-            let mut name: String = mg.chars().skip(5).collect();
-            name = name.trim_end_matches(".up.sql").to_string();
+        migrations: Vec<&Migration>,
+        dry_run: bool,
+    ) -> Result<()> {
+        // a dry run can't roll back ddl that commits implicitly, so it runs nothing
+        if dry_run && !self.dialect.transactional_ddl() {
+            return Ok(());
+        }
 
-            let migration_query = fs::read_to_string("./migrations/".to_owned() + mg)?;
+        let mut conn = self.acquire().await?;
+        let mut outside = self.outside_connection().await?;
+        let mut tx = conn.begin().await?;
+        let mut finished = Vec::new();
 
-            if let Err(e) = self.insert_migration(&name, &mut tx).await {
-                return Err(format!("error when inserting to migration {}, {}", name, e))?;
-            }
+        for mg in migrations {
+            let name = &mg.name;
+            let checksum = checksum(mg.up_sql()?);
 
-            match self.apply_migration(&migration_query, &mut tx).await {
-                Ok(_) => {
-                    println!("applied migration {}", name)
+            match outside.as_deref_mut() {
+                Some(outside) => {
+                    self.insert_migration(name, &checksum, true, outside)
+                        .await?
                 }
-                Err(e) => {
-                    return Err(format!("error when migrating {}, {}", name, e))?;
+                None => {
+                    self.insert_migration(name, &checksum, false, &mut tx)
+                        .await?
                 }
             }
+
+            let duration = execute_migration(mg, "up", &self.database, &mut tx).await?;
+
+            match outside {
+                Some(_) => finished.push((name, duration)),
+                None => self.finish_migration(name, duration, &mut tx).await?,
+            }
         }
 
-        tx.commit().await?;
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
+        }
+
+        if let Some(outside) = outside.as_deref_mut() {
+            for (name, duration) in finished {
+                self.finish_migration(name, duration, outside).await?;
+            }
+        }
 
         Ok(())
     }
 
-    async fn down_migration_transaction(
-        &self,
-        down_migrations: Vec<&String>,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut tx = self.pool.begin().await?;
-
-        for i in (0..down_migrations.len()).rev() {
-            let mg = down_migrations[i as usize];
-            let mut name: String = mg.chars().skip(5).collect();
-            name = name.trim_end_matches(".down.sql").to_string();
+    pub async fn down_migration_transaction(&self, down_migrations: Vec<&Migration>) -> Result<()> {
+        let mut conn = self.acquire().await?;
+        let mut outside = self.outside_connection().await?;
+        let mut tx = conn.begin().await?;
+        let mut finished = Vec::new();
 
-            let migration_query = fs::read_to_string("./migrations/".to_owned() + mg)?;
-
-            match self.delete_migration(&name, &mut tx).await {
-                Ok(_) => {}
-                Err(err) => {
-                    return Err(err.into());
-                }
+        for mg in down_migrations {
+            if let Some(outside) = outside.as_deref_mut() {
+                self.set_dirty(&mg.name, true, outside).await?;
             }
 
-            match self.revert_migration(&migration_query, &mut tx).await {
-                Ok(_) => {
-                    println!("removed migration {}", name)
-                }
-                Err(err) => {
-                    return Err(format!("error when migrating {}, {}", name, err))?;
+            let duration = execute_migration(mg, "down", &self.database, &mut tx).await?;
+
+            match outside {
+                Some(_) => finished.push((&mg.name, duration)),
+                None => {
+                    self.delete_migration(&mg.name, Some(duration), &mut tx)
+                        .await?
                 }
             }
         }
 
         tx.commit().await?;
 
+        if let Some(outside) = outside.as_deref_mut() {
+            for (name, duration) in finished {
+                self.delete_migration(name, Some(duration), outside).await?;
+            }
+        }
+
         Ok(())
     }
-}
 
-pub struct MySqlDb {
-    pub pool: Pool<MySql>,
-}
+    // for migrations that can't run inside a transaction, records the migration as dirty
+    // until its statements succeed
+    pub async fn up_migration_no_transaction(&self, migration: &Migration) -> Result<()> {
+        let mut conn = self.acquire().await?;
+        let checksum = checksum(migration.up_sql()?);
 
-#[async_trait]
-impl Db for MySqlDb {
-    type A = MySql;
-    type B = MySqlDb;
-    async fn new_connection(database_url: String) -> Result<MySqlDb, Box<dyn Error>> {
-        let pool = sqlx::mysql::MySqlPool::connect(&database_url).await?;
+        self.insert_migration(&migration.name, &checksum, true, &mut conn)
+            .await?;
 
-        return Ok(MySqlDb { pool });
-    }
+        let duration = execute_migration(migration, "up", &self.database, &mut conn).await?;
 
-    async fn ping_db(&self) -> Result<(), Box<dyn Error>> {
-        let result = sqlx::query("SELECT 1 + 1 as sum")
-            .fetch_one(&self.pool)
+        self.finish_migration(&migration.name, duration, &mut conn)
             .await?;
 
-        let _s: i32 = result.get("sum");
-
         Ok(())
     }
 
-    async fn table_exists(&self) -> Result<bool, Box<dyn Error>> {
-        let table_exits_sql = "SELECT EXISTS (
-            SELECT FROM information_schema.tables 
-            where  table_name = 'db_migrations'
-            );";
+    pub async fn down_migration_no_transaction(&self, migration: &Migration) -> Result<()> {
+        let mut conn = self.acquire().await?;
 
-        let results = sqlx::query(table_exits_sql).fetch_one(&self.pool).await?;
-        let tb_exists: bool = results.get("exists");
+        self.set_dirty(&migration.name, true, &mut conn).await?;
 
-        Ok(tb_exists)
+        let duration = execute_migration(migration, "down", &self.database, &mut conn).await?;
+
+        self.delete_migration(&migration.name, Some(duration), &mut conn)
+            .await?;
+
+        Ok(())
     }
 
-    async fn create_migration_table(&self) -> Result<(), Box<dyn Error>> {
-        let create_table_sql = "create table db_migrations(
-            id serial primary key,
-            name text unique,
-            valid bool ,
-            created_at timestamp not null DEFAULT now(),
-            deleted_at timestamp
-        );";
+    // marks a dirty migration as applied, or removes its record when it isn't
+    pub async fn resolve_dirty(&self, name: &str, applied: bool) -> Result<()> {
+        let mut conn = self.acquire().await?;
 
-        sqlx::query(create_table_sql).execute(&self.pool).await?;
+        if applied {
+            self.set_dirty(name, false, &mut conn).await?;
+        } else {
+            self.delete_migration(name, None, &mut conn).await?;
+        }
 
         Ok(())
     }
+}
 
-    async fn get_migration_table_count(&self) -> Result<usize, Box<dyn Error>> {
-        let result = sqlx::query("SELECT id from db_migrations")
-            .fetch_all(&self.pool)
-            .await?;
-        let count = result.len();
+#[async_trait]
+impl Db for PostgresDb {
+    type A = Postgres;
 
-        Ok(count)
+    fn from_pool(pool: Pool<Postgres>, table: MigrationTable, run: RunInfo) -> PostgresDb {
+        SqlDb::new(pool, DatabaseType::Postgres, &PostgresDialect, table, run)
     }
 
-    async fn get_last_migration(&self) -> Result<String, Box<dyn Error>> {
-        let result = sqlx::query("Select name from db_migrations order by id desc limit 1;")
-            .fetch_one(&self.pool)
-            .await?;
+    async fn new_connection(
+        database_url: String,
+        table: MigrationTable,
+        run: RunInfo,
+    ) -> Result<PostgresDb> {
+        let pool = sqlx::postgres::PgPool::connect(&database_url)
+            .await
+            .map_err(MigratourError::Connection)?;
 
-        let name = result.try_get("name")?;
-
-        Ok(name)
+        Ok(Self::from_pool(pool, table, run))
     }
 
-    // async fn new_transaction(&self) -> Result<sqlx::Transaction<'_, Postgres>, Box<dyn Error>> {
-    //     let tx: sqlx::Transaction<'_, Postgres> = self.pool.begin().await?;
-    //     Ok(tx)
-    // }
+    // the advisory lock belongs to the session that took it, so its connection is kept aside
+    async fn acquire_lock(&self, timeout: Duration) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        let started = Instant::now();
+
+        loop {
+            let result = sqlx::query("SELECT pg_try_advisory_lock($1) as locked")
+                .bind(i64::from_be_bytes(self.table.lock_key()))
+                .fetch_one(&mut *conn)
+                .await?;
+            let locked: bool = result.try_get("locked")?;
+
+            if locked {
+                *self.lock.lock().await = Some(Lock::Session(conn));
+                return Ok(());
+            }
+            if started.elapsed() >= timeout {
+                return Err(MigratourError::LockTimeout(timeout));
+            }
 
-    async fn insert_migration(
-        &self,
-        name: &String,
-        tx: &mut sqlx::Transaction<'_, sqlx::MySql>,
-    ) -> Result<(), Box<dyn Error>> {
-        sqlx::query("INSERT INTO db_migrations(name, valid) VALUES ($1, $2);")
-            .bind(&name)
-            .bind(true)
-            .execute(&mut **tx)
-            .await?;
+            tokio::time::sleep(LOCK_RETRY_INTERVAL).await;
+        }
+    }
+
+    async fn release_lock(&self) -> Result<()> {
+        if let Some(Lock::Session(mut conn)) = self.lock.lock().await.take() {
+            sqlx::query("SELECT pg_advisory_unlock($1)")
+                .bind(i64::from_be_bytes(self.table.lock_key()))
+                .execute(&mut *conn)
+                .await?;
+        }
 
         Ok(())
     }
+}
 
-    async fn apply_migration(
-        &self,
-        migration_query: &String,
-        tx: &mut sqlx::Transaction<'_, sqlx::MySql>,
-    ) -> Result<(), Box<dyn Error>> {
-        let queries: Vec<&str> = migration_query.split(';').collect();
+#[async_trait]
+impl Db for MySqlDb {
+    type A = MySql;
 
-        for query in queries {
-            sqlx::query(query).execute(&mut **tx).await?;
-        }
+    fn from_pool(pool: Pool<MySql>, table: MigrationTable, run: RunInfo) -> MySqlDb {
+        SqlDb::new(pool, DatabaseType::MySql, &MySqlDialect, table, run)
+    }
 
-        Ok(())
+    async fn new_connection(
+        database_url: String,
+        table: MigrationTable,
+        run: RunInfo,
+    ) -> Result<MySqlDb> {
+        let pool = sqlx::mysql::MySqlPool::connect(&database_url)
+            .await
+            .map_err(MigratourError::Connection)?;
+
+        Ok(Self::from_pool(pool, table, run))
     }
 
-    async fn delete_migration(
-        &self,
-        name: &String,
-        tx: &mut sqlx::Transaction<'_, sqlx::MySql>,
-    ) -> Result<(), Box<dyn Error>> {
-        sqlx::query("DELETE from db_migrations where name = $1;")
-            .bind(name)
-            .execute(&mut **tx)
+    async fn acquire_lock(&self, timeout: Duration) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+
+        // GET_LOCK waits up to the timeout itself, 1 means acquired and 0 means timed out
+        let result = sqlx::query("SELECT GET_LOCK(?, ?) as locked")
+            .bind(mysql_lock_name(&self.table))
+            .bind(timeout.as_secs())
+            .fetch_one(&mut *conn)
             .await?;
+        let locked: Option<i64> = result.try_get("locked")?;
+
+        if locked != Some(1) {
+            return Err(MigratourError::LockTimeout(timeout));
+        }
+
+        *self.lock.lock().await = Some(Lock::Session(conn));
 
         Ok(())
     }
 
-    async fn revert_migration(
-        &self,
-        migration_query: &String,
-        tx: &mut sqlx::Transaction<'_, sqlx::MySql>,
-    ) -> Result<(), Box<dyn Error>> {
-        let queries: Vec<&str> = migration_query.split(';').collect();
-
-        for query in queries {
-            sqlx::query(query).execute(&mut **tx).await?;
+    async fn release_lock(&self) -> Result<()> {
+        if let Some(Lock::Session(mut conn)) = self.lock.lock().await.take() {
+            sqlx::query("SELECT RELEASE_LOCK(?)")
+                .bind(mysql_lock_name(&self.table))
+                .execute(&mut *conn)
+                .await?;
         }
 
         Ok(())
     }
+}
 
-    async fn up_migration_transaction(
-        &self,
-        unapplied_migrations: Vec<&String>,
-        migrations_to_apply: i32,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut tx: sqlx::Transaction<'_, MySql> = self.pool.begin().await?;
+#[async_trait]
+impl Db for SqliteDb {
+    type A = Sqlite;
 
-        for i in 0..migrations_to_apply {
-            let mg = unapplied_migrations[i as usize];
-            let mut name: String = mg.chars().skip(5).collect();
-            name = name.trim_end_matches(".up.sql").to_string();
+    fn from_pool(pool: Pool<Sqlite>, table: MigrationTable, run: RunInfo) -> SqliteDb {
+        SqlDb::new(pool, DatabaseType::Sqlite, &SqliteDialect, table, run)
+    }
 
-            let migration_query = fs::read_to_string("./migrations/".to_owned() + mg)?;
+    async fn new_connection(
+        database_url: String,
+        table: MigrationTable,
+        run: RunInfo,
+    ) -> Result<SqliteDb> {
+        // in-memory urls ("sqlite::memory:") are shared between the pool's connections by sqlx,
+        // file urls create the database file on first use
+        let options = SqliteConnectOptions::from_str(&database_url)
+            .map_err(MigratourError::Connection)?
+            .create_if_missing(true);
+        let pool = sqlx::sqlite::SqlitePool::connect_with(options)
+            .await
+            .map_err(MigratourError::Connection)?;
+
+        Ok(Self::from_pool(pool, table, run))
+    }
 
-            if let Err(e) = self.insert_migration(&name, &mut tx).await {
-                return Err(format!("error when inserting to migration {}, {}", name, e))?;
-            }
+    // an exclusive lock on the database file would also block this process' own pool, so the
+    // lock is an exclusive lock on a "<database>.migratour-lock" file next to it instead
+    async fn acquire_lock(&self, timeout: Duration) -> Result<()> {
+        let result = sqlx::query("SELECT file FROM pragma_database_list WHERE name = 'main'")
+            .fetch_one(&self.pool)
+            .await?;
+        let file: String = result.try_get("file")?;
 
-            match self.apply_migration(&migration_query, &mut tx).await {
-                Ok(_) => {
-                    println!("applied migration {}", name)
-                }
-                Err(e) => {
-                    return Err(format!("error when migrating {}, {}", name, e))?;
-                }
-            }
+        // in-memory databases can't be shared with another process
+        if file.is_empty() {
+            return Ok(());
         }
 
-        tx.commit().await?;
-
-        Ok(())
-    }
-
-    async fn down_migration_transaction(
-        &self,
-        down_migrations: Vec<&String>,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut tx = self.pool.begin().await?;
+        let mut conn = SqliteConnectOptions::new()
+            .filename(file + ".migratour-lock")
+            .create_if_missing(true)
+            .locking_mode(SqliteLockingMode::Exclusive)
+            .busy_timeout(timeout)
+            .connect()
+            .await?;
 
-        for i in (0..down_migrations.len()).rev() {
-            let mg = down_migrations[i as usize];
-            let mut name: String = mg.chars().skip(5).collect();
-            name = name.trim_end_matches(".down.sql").to_string();
+        // in exclusive locking mode the lock is kept after the transaction ends,
+        // until the connection is closed
+        if sqlx::query("BEGIN EXCLUSIVE")
+            .execute(&mut conn)
+            .await
+            .is_err()
+        {
+            return Err(MigratourError::LockTimeout(timeout));
+        }
+        sqlx::query("COMMIT").execute(&mut conn).await?;
 
-            let migration_query = fs::read_to_string("./migrations/".to_owned() + mg)?;
+        *self.lock.lock().await = Some(Lock::File(conn));
 
-            match self.delete_migration(&name, &mut tx).await {
-                Ok(_) => {}
-                Err(err) => {
-                    return Err(err.into());
-                }
-            }
+        Ok(())
+    }
 
-            match self.revert_migration(&migration_query, &mut tx).await {
-                Ok(_) => {
-                    println!("removed migration {}", name)
-                }
-                Err(err) => {
-                    return Err(format!("error when migrating {}, {}", name, err))?;
-                }
-            }
+    async fn release_lock(&self) -> Result<()> {
+        if let Some(Lock::File(conn)) = self.lock.lock().await.take() {
+            conn.close().await?;
         }
 
-        tx.commit().await?;
-
         Ok(())
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 pub enum DbExe {
     PgExe(PostgresDb),
     MySqlExe(MySqlDb),
+    SqliteExe(SqliteDb),
 }
 
 impl DbExe {
-    pub async fn ping_db(&self) -> Result<(), Box<dyn Error>> {
+    pub fn database(&self) -> DatabaseType {
+        match self {
+            DbExe::PgExe(_) => DatabaseType::Postgres,
+            DbExe::MySqlExe(_) => DatabaseType::MySql,
+            DbExe::SqliteExe(_) => DatabaseType::Sqlite,
+        }
+    }
+
+    pub async fn ping_db(&self) -> Result<()> {
         match self {
             DbExe::PgExe(pg) => pg.ping_db().await?,
             DbExe::MySqlExe(m) => m.ping_db().await?,
+            DbExe::SqliteExe(sq) => sq.ping_db().await?,
         }
 
         Ok(())
     }
 
-    pub async fn table_exists(&self) -> Result<bool, Box<dyn Error>> {
+    pub async fn table_exists(&self) -> Result<bool> {
         let tb_exists = match self {
             DbExe::PgExe(pg) => pg.table_exists().await?,
             DbExe::MySqlExe(m) => m.table_exists().await?,
+            DbExe::SqliteExe(sq) => sq.table_exists().await?,
         };
 
         Ok(tb_exists)
     }
 
-    pub async fn create_migration_table(&self) -> Result<(), Box<dyn Error>> {
+    pub async fn create_migration_table(&self) -> Result<()> {
         match self {
             DbExe::PgExe(pg) => pg.create_migration_table().await?,
             DbExe::MySqlExe(m) => m.create_migration_table().await?,
+            DbExe::SqliteExe(sq) => sq.create_migration_table().await?,
         }
 
         Ok(())
     }
 
-    pub async fn get_migration_table_count(&self) -> Result<usize, Box<dyn Error>> {
+    pub async fn migration_table_version(&self) -> Result<i32> {
+        let version = match self {
+            DbExe::PgExe(pg) => pg.migration_table_version().await?,
+            DbExe::MySqlExe(m) => m.migration_table_version().await?,
+            DbExe::SqliteExe(sq) => sq.migration_table_version().await?,
+        };
+
+        Ok(version)
+    }
+
+    pub async fn upgrade_migration_table(&self, from: i32) -> Result<()> {
+        match self {
+            DbExe::PgExe(pg) => pg.upgrade_migration_table(from).await?,
+            DbExe::MySqlExe(m) => m.upgrade_migration_table(from).await?,
+            DbExe::SqliteExe(sq) => sq.upgrade_migration_table(from).await?,
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_migration_table_count(&self) -> Result<usize> {
         let count = match self {
             DbExe::PgExe(pg) => pg.get_migration_table_count().await?,
             DbExe::MySqlExe(m) => m.get_migration_table_count().await?,
+            DbExe::SqliteExe(sq) => sq.get_migration_table_count().await?,
         };
 
         Ok(count)
     }
 
-    // async fn new_transaction(&self) -> Result<sqlx::Transaction<'_, Postgres>, Box<dyn Error>> {
+    // async fn new_transaction(&self) -> Result<sqlx::Transaction<'_, Postgres>> {
     //     let tx: sqlx::Transaction<'_, Postgres> = self.pool.begin().await?;
     //     Ok(tx)
     // }
 
     pub async fn up_migration_transaction(
         &self,
-        unapplied_migrations: Vec<&String>,
-        migrations_to_apply: i32,
-    ) -> Result<(), Box<dyn Error>> {
-        if unapplied_migrations.len() < migrations_to_apply as usize {
-            return Err(format!(
-                "unapplied migrations {} is less than migrations {} to apply",
-                unapplied_migrations.len(),
-                migrations_to_apply
-            ))?;
-        }
-
+        migrations: Vec<&Migration>,
+        dry_run: bool,
+    ) -> Result<()> {
         match self {
             DbExe::MySqlExe(m) => {
-                m.up_migration_transaction(unapplied_migrations, migrations_to_apply)
-                    .await?;
+                m.up_migration_transaction(migrations, dry_run).await?;
             }
             DbExe::PgExe(pg) => {
-                pg.up_migration_transaction(unapplied_migrations, migrations_to_apply)
-                    .await?;
+                pg.up_migration_transaction(migrations, dry_run).await?;
+            }
+            DbExe::SqliteExe(sq) => {
+                sq.up_migration_transaction(migrations, dry_run).await?;
             }
         }
 
         Ok(())
     }
 
-    pub async fn get_last_migration(&self) -> Result<String, Box<dyn Error>> {
+    pub async fn up_migration_no_transaction(&self, migration: &Migration) -> Result<()> {
+        match self {
+            DbExe::MySqlExe(m) => m.up_migration_no_transaction(migration).await?,
+            DbExe::PgExe(pg) => pg.up_migration_no_transaction(migration).await?,
+            DbExe::SqliteExe(sq) => sq.up_migration_no_transaction(migration).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn down_migration_no_transaction(&self, migration: &Migration) -> Result<()> {
+        match self {
+            DbExe::MySqlExe(m) => m.down_migration_no_transaction(migration).await?,
+            DbExe::PgExe(pg) => pg.down_migration_no_transaction(migration).await?,
+            DbExe::SqliteExe(sq) => sq.down_migration_no_transaction(migration).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn get_last_migration(&self) -> Result<Option<String>> {
         let name = match self {
             DbExe::MySqlExe(m) => m.get_last_migration().await?,
             DbExe::PgExe(pg) => pg.get_last_migration().await?,
+            DbExe::SqliteExe(sq) => sq.get_last_migration().await?,
         };
 
         Ok(name)
     }
 
-    pub async fn down_migration_transaction(
-        &self,
-        down_migrations: Vec<&String>,
-    ) -> Result<(), Box<dyn Error>> {
+    pub async fn get_applied_migrations(&self) -> Result<Vec<AppliedMigration>> {
+        let applied = match self {
+            DbExe::MySqlExe(m) => m.get_applied_migrations().await?,
+            DbExe::PgExe(pg) => pg.get_applied_migrations().await?,
+            DbExe::SqliteExe(sq) => sq.get_applied_migrations().await?,
+        };
+
+        Ok(applied)
+    }
+
+    pub async fn get_migration_history(&self) -> Result<Vec<HistoryEntry>> {
+        let history = match self {
+            DbExe::MySqlExe(m) => m.get_migration_history().await?,
+            DbExe::PgExe(pg) => pg.get_migration_history().await?,
+            DbExe::SqliteExe(sq) => sq.get_migration_history().await?,
+        };
+
+        Ok(history)
+    }
+
+    pub async fn down_migration_transaction(&self, down_migrations: Vec<&Migration>) -> Result<()> {
         match self {
             DbExe::MySqlExe(m) => m.down_migration_transaction(down_migrations).await?,
             DbExe::PgExe(pg) => pg.down_migration_transaction(down_migrations).await?,
+            DbExe::SqliteExe(sq) => sq.down_migration_transaction(down_migrations).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn resolve_dirty(&self, name: &str, applied: bool) -> Result<()> {
+        match self {
+            DbExe::MySqlExe(m) => m.resolve_dirty(name, applied).await?,
+            DbExe::PgExe(pg) => pg.resolve_dirty(name, applied).await?,
+            DbExe::SqliteExe(sq) => sq.resolve_dirty(name, applied).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn lock(&self, timeout: Duration) -> Result<()> {
+        match self {
+            DbExe::MySqlExe(m) => m.acquire_lock(timeout).await?,
+            DbExe::PgExe(pg) => pg.acquire_lock(timeout).await?,
+            DbExe::SqliteExe(sq) => sq.acquire_lock(timeout).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn unlock(&self) -> Result<()> {
+        match self {
+            DbExe::MySqlExe(m) => m.release_lock().await?,
+            DbExe::PgExe(pg) => pg.release_lock().await?,
+            DbExe::SqliteExe(sq) => sq.release_lock().await?,
         }
         Ok(())
     }
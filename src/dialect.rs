@@ -0,0 +1,523 @@
+use sha2::{Digest, Sha256};
+
+// layout version of the migrations table, stored in its "<table>_meta" table. bump it with
+// every change to the table and add the change to Dialect::upgrade_migration_table
+pub const MIGRATION_TABLE_VERSION: i32 = 7;
+
+// tables created before the layout was versioned have no meta table, their version is
+// told apart by the last column they got
+// who ran each apply and revert, added in layout version 5
+const RUN_COLUMNS: [&str; 10] = [
+    "applied_by text",
+    "applied_host text",
+    "applied_migratour_version text",
+    "applied_environment text",
+    "apply_duration_ms bigint",
+    "reverted_by text",
+    "reverted_host text",
+    "reverted_migratour_version text",
+    "reverted_environment text",
+    "revert_duration_ms bigint",
+];
+
+// the order of the applies and reverts, and whether a revert was `repair --not-applied`
+// discarding a dirty migration, added in layout version 6
+const EVENT_COLUMNS: [&str; 3] = [
+    "applied_seq bigint",
+    "reverted_seq bigint",
+    "discarded boolean not null default false",
+];
+
+// the columns added after the first layouts, which every dialect creates the same
+fn layout_columns() -> Vec<&'static str> {
+    RUN_COLUMNS.iter().chain(&EVENT_COLUMNS).copied().collect()
+}
+
+pub const UNVERSIONED_LAYOUTS: &[(&str, i32)] = &[("dirty", 3), ("checksum", 2)];
+
+// the table recording the applied migrations, optionally in another schema (or database on
+// mysql, attached database on sqlite) than the current one
+#[derive(Debug, Clone)]
+pub struct MigrationTable {
+    pub schema: Option<String>,
+    pub name: String,
+}
+
+impl MigrationTable {
+    pub fn qualified(&self) -> String {
+        match &self.schema {
+            Some(schema) => format!("{}.{}", schema, self.name),
+            None => self.name.clone(),
+        }
+    }
+
+    pub fn meta(&self) -> MigrationTable {
+        MigrationTable {
+            schema: self.schema.clone(),
+            name: format!("{}_meta", self.name),
+        }
+    }
+
+    // key of the migration lock, so that apps keeping separate histories in one database
+    // don't wait on each other
+    pub fn lock_key(&self) -> [u8; 8] {
+        let digest = Sha256::digest(format!("migratour:{}", self.qualified()).as_bytes());
+        let mut key = [0; 8];
+        key.copy_from_slice(&digest[..8]);
+        key
+    }
+}
+
+// the sql the backends need from the database. a dialect only implements what differs
+// between databases, the queries on the migrations table are shared
+pub trait Dialect: Sync {
+    // bind parameter number n, counting from 1
+    fn placeholder(&self, n: usize) -> String;
+
+    // type to cast timestamps to when reading them as text
+    fn text_type(&self) -> &'static str;
+
+    // whether ddl can be rolled back. mysql commits it implicitly, so migrations are marked
+    // dirty outside of their transaction and dry runs run nothing
+    fn transactional_ddl(&self) -> bool {
+        true
+    }
+
+    fn create_migration_table(&self, table: &str) -> String;
+
+    // counts the tables with the given name, in its schema or in the current schema or
+    // database, as a "count" column
+    fn table_exists(&self, table: &MigrationTable) -> String;
+
+    // creates the schema of the migrations table when it is missing, if the database can
+    fn create_schema(&self, _schema: &str) -> Option<String> {
+        None
+    }
+
+    // migrations are looked up by name, added in layout version 7. without an index, an
+    // update by name locks every row on mysql
+    fn create_name_index(&self, table: &MigrationTable) -> String {
+        format!(
+            "CREATE INDEX {}_name_idx ON {} (name);",
+            table.name,
+            table.qualified()
+        )
+    }
+
+    // names can repeat since a reverted migration keeps its row when it is applied again, so
+    // tables created before that lose their unique constraint on the name
+    fn drop_name_unique(&self, table: &MigrationTable) -> Vec<String>;
+
+    // counts the columns of the table with the given name, as a "count" column. table names
+    // are checked to be identifiers, so they are written into the query
+    fn column_exists(&self, table: &MigrationTable, column: &str) -> String;
+
+    // records the current layout version of the migrations table in its meta table
+    fn set_table_version(&self, table: &MigrationTable) -> Vec<String> {
+        let meta = table.meta().qualified();
+
+        vec![
+            format!(
+                "CREATE TABLE IF NOT EXISTS {}(version integer not null);",
+                meta
+            ),
+            format!("DELETE FROM {};", meta),
+            format!(
+                "INSERT INTO {}(version) VALUES ({});",
+                meta, MIGRATION_TABLE_VERSION
+            ),
+        ]
+    }
+
+    fn table_version(&self, table: &MigrationTable) -> String {
+        format!("SELECT version FROM {};", table.meta().qualified())
+    }
+
+    // statements bringing a migrations table of an older layout version to the current one
+    fn upgrade_migration_table(&self, table: &MigrationTable, from: i32) -> Vec<String> {
+        let qualified = table.qualified();
+        let mut columns = Vec::new();
+
+        if from < 2 {
+            columns.push("checksum text");
+        }
+        if from < 3 {
+            columns.push("dirty boolean not null default false");
+        }
+        if from < 5 {
+            columns.extend(RUN_COLUMNS);
+        }
+        if from < 6 {
+            columns.extend(EVENT_COLUMNS);
+        }
+
+        let mut steps: Vec<String> = columns
+            .iter()
+            .map(|column| format!("ALTER TABLE {} ADD COLUMN {};", qualified, column))
+            .collect();
+
+        // reverts used to leave the dirty flag of migrations reverted outside of a transaction
+        if from < 6 {
+            steps.push(format!(
+                "UPDATE {} SET dirty = false WHERE NOT valid;",
+                qualified
+            ));
+        }
+
+        // goes after the columns, sqlite rebuilds the table with every column of the current
+        // layout and without its indexes
+        if from < 4 {
+            steps.extend(self.drop_name_unique(table));
+        }
+        if from < 7 {
+            steps.push(self.create_name_index(table));
+        }
+
+        steps.extend(self.set_table_version(table));
+        steps
+    }
+
+    // the applies and reverts of the table are numbered in the order they happen, as the
+    // timestamps can be equal
+    fn last_seq(&self, table: &str) -> String {
+        format!(
+            "SELECT COALESCE(MAX(applied_seq), 0) AS applied, COALESCE(MAX(reverted_seq), 0) AS reverted FROM {};",
+            table
+        )
+    }
+
+    // rows recorded before the applies and reverts were numbered, to number them in the
+    // order of their timestamps when upgrading
+    fn unnumbered_events(&self, table: &str) -> String {
+        format!(
+            "SELECT CAST(id AS {0}) AS row_id, name, CAST(created_at AS {0}) AS applied_at, CAST(deleted_at AS {0}) AS reverted_at FROM {1} ORDER BY id;",
+            self.text_type(),
+            table
+        )
+    }
+
+    fn set_event_seq(&self, table: &str) -> String {
+        format!(
+            "UPDATE {} SET applied_seq = {}, reverted_seq = {} WHERE id = {};",
+            table,
+            self.placeholder(1),
+            self.placeholder(2),
+            self.placeholder(3)
+        )
+    }
+
+    fn insert_migration(&self, table: &str) -> String {
+        let values: Vec<String> = (1..=9).map(|n| self.placeholder(n)).collect();
+
+        format!(
+            "INSERT INTO {}(name, valid, checksum, dirty, applied_by, applied_host, applied_migratour_version, applied_environment, applied_seq) VALUES ({});",
+            table,
+            values.join(", ")
+        )
+    }
+
+    // marks a migration as clean once its statements all ran, with how long they took
+    fn finish_migration(&self, table: &str) -> String {
+        format!(
+            "UPDATE {} SET dirty = false, apply_duration_ms = {} WHERE name = {} AND valid;",
+            table,
+            self.placeholder(1),
+            self.placeholder(2)
+        )
+    }
+
+    fn set_dirty(&self, table: &str) -> String {
+        format!(
+            "UPDATE {} SET dirty = {} WHERE name = {} AND valid;",
+            table,
+            self.placeholder(1),
+            self.placeholder(2)
+        )
+    }
+
+    // reverted migrations keep their row, marked invalid, for the history
+    fn delete_migration(&self, table: &str) -> String {
+        format!(
+            "UPDATE {} SET valid = false, dirty = false, deleted_at = CURRENT_TIMESTAMP, reverted_by = {}, reverted_host = {}, reverted_migratour_version = {}, reverted_environment = {}, revert_duration_ms = {}, reverted_seq = {}, discarded = {} WHERE name = {} AND valid;",
+            table,
+            self.placeholder(1),
+            self.placeholder(2),
+            self.placeholder(3),
+            self.placeholder(4),
+            self.placeholder(5),
+            self.placeholder(6),
+            self.placeholder(7),
+            self.placeholder(8)
+        )
+    }
+
+    fn applied_migrations(&self, table: &str) -> String {
+        format!(
+            "SELECT name, CAST(created_at AS {}) AS applied_at, checksum, dirty, applied_by, applied_host, applied_migratour_version, applied_environment, apply_duration_ms FROM {} WHERE valid ORDER BY id;",
+            self.text_type(),
+            table
+        )
+    }
+
+    fn last_migration(&self, table: &str) -> String {
+        format!(
+            "SELECT name FROM {} WHERE valid ORDER BY id DESC LIMIT 1;",
+            table
+        )
+    }
+
+    fn migration_count(&self, table: &str) -> String {
+        format!("SELECT COUNT(*) AS count FROM {} WHERE valid;", table)
+    }
+
+    // every row, reverted ones included
+    fn migration_history(&self, table: &str) -> String {
+        format!(
+            "SELECT name, checksum, dirty, CAST(created_at AS {0}) AS applied_at, applied_by, applied_host, applied_migratour_version, applied_environment, apply_duration_ms, applied_seq, CAST(deleted_at AS {0}) AS reverted_at, reverted_by, reverted_host, reverted_migratour_version, reverted_environment, revert_duration_ms, reverted_seq, discarded FROM {1} ORDER BY id;",
+            self.text_type(),
+            table
+        )
+    }
+}
+
+pub struct PostgresDialect;
+
+// postgres folds unquoted names to lower case, and the migrations table is created with
+// unquoted names, so the catalog holds them lower cased
+fn fold_case(name: &str) -> String {
+    name.to_lowercase()
+}
+
+fn postgres_schema(table: &MigrationTable) -> String {
+    match &table.schema {
+        Some(schema) => format!("'{}'", fold_case(schema)),
+        None => "current_schema()".to_string(),
+    }
+}
+
+impl Dialect for PostgresDialect {
+    fn placeholder(&self, n: usize) -> String {
+        format!("${}", n)
+    }
+
+    fn text_type(&self) -> &'static str {
+        "text"
+    }
+
+    fn create_migration_table(&self, table: &str) -> String {
+        format!(
+            "CREATE TABLE {}(
+            id serial primary key,
+            name text not null,
+            valid boolean,
+            created_at timestamp not null default now(),
+            deleted_at timestamp,
+            checksum text,
+            dirty boolean not null default false,
+            {}
+        );",
+            table,
+            layout_columns().join(",\n            ")
+        )
+    }
+
+    fn table_exists(&self, table: &MigrationTable) -> String {
+        format!(
+            "SELECT COUNT(*) AS count FROM information_schema.tables
+            WHERE table_name = '{}' AND table_schema = {};",
+            fold_case(&table.name),
+            postgres_schema(table)
+        )
+    }
+
+    fn create_schema(&self, schema: &str) -> Option<String> {
+        Some(format!("CREATE SCHEMA IF NOT EXISTS {};", schema))
+    }
+
+    // the constraint got the default name of a unique column
+    fn drop_name_unique(&self, table: &MigrationTable) -> Vec<String> {
+        vec![format!(
+            "ALTER TABLE {} DROP CONSTRAINT IF EXISTS {}_name_key;",
+            table.qualified(),
+            table.name
+        )]
+    }
+
+    fn column_exists(&self, table: &MigrationTable, column: &str) -> String {
+        format!(
+            "SELECT COUNT(*) AS count FROM information_schema.columns
+            WHERE table_name = '{}' AND table_schema = {} AND column_name = '{}';",
+            fold_case(&table.name),
+            postgres_schema(table),
+            column
+        )
+    }
+}
+
+// also used for mariadb
+pub struct MySqlDialect;
+
+impl Dialect for MySqlDialect {
+    fn placeholder(&self, _n: usize) -> String {
+        "?".to_string()
+    }
+
+    fn text_type(&self) -> &'static str {
+        "char"
+    }
+
+    fn create_migration_table(&self, table: &str) -> String {
+        format!(
+            "CREATE TABLE {}(
+            id bigint unsigned auto_increment primary key,
+            name varchar(255) not null,
+            valid boolean,
+            created_at timestamp not null default current_timestamp,
+            deleted_at timestamp null default null,
+            checksum varchar(64),
+            dirty boolean not null default false,
+            {}
+        );",
+            table,
+            layout_columns().join(",\n            ")
+        )
+    }
+
+    fn transactional_ddl(&self) -> bool {
+        false
+    }
+
+    fn table_exists(&self, table: &MigrationTable) -> String {
+        let schema = match &table.schema {
+            Some(schema) => format!("'{}'", schema),
+            None => "DATABASE()".to_string(),
+        };
+
+        format!(
+            "SELECT COUNT(*) AS count FROM information_schema.tables
+            WHERE table_name = '{}' AND table_schema = {};",
+            table.name, schema
+        )
+    }
+
+    // the unique index got the default name of a unique column
+    fn drop_name_unique(&self, table: &MigrationTable) -> Vec<String> {
+        vec![format!(
+            "ALTER TABLE {} DROP INDEX name;",
+            table.qualified()
+        )]
+    }
+
+    fn column_exists(&self, table: &MigrationTable, column: &str) -> String {
+        let schema = match &table.schema {
+            Some(schema) => format!("'{}'", schema),
+            None => "DATABASE()".to_string(),
+        };
+
+        format!(
+            "SELECT COUNT(*) AS count FROM information_schema.columns
+            WHERE table_name = '{}' AND table_schema = {} AND column_name = '{}';",
+            table.name, schema, column
+        )
+    }
+}
+
+pub struct SqliteDialect;
+
+impl Dialect for SqliteDialect {
+    fn placeholder(&self, _n: usize) -> String {
+        "?".to_string()
+    }
+
+    fn text_type(&self) -> &'static str {
+        "text"
+    }
+
+    fn create_migration_table(&self, table: &str) -> String {
+        format!(
+            "CREATE TABLE {}(
+            id integer primary key autoincrement,
+            name text not null,
+            valid boolean,
+            created_at timestamp not null default current_timestamp,
+            deleted_at timestamp,
+            checksum text,
+            dirty boolean not null default false,
+            {}
+        );",
+            table,
+            layout_columns().join(",\n            ")
+        )
+    }
+
+    // the schema is the name of an attached database, whose tables are listed in its own
+    // sqlite_master
+    fn table_exists(&self, table: &MigrationTable) -> String {
+        let master = match &table.schema {
+            Some(schema) => format!("{}.sqlite_master", schema),
+            None => "sqlite_master".to_string(),
+        };
+
+        format!(
+            "SELECT COUNT(*) AS count FROM {} WHERE type = 'table' AND name = '{}';",
+            master, table.name
+        )
+    }
+
+    // the index goes in the attached database of the table, named with its schema
+    fn create_name_index(&self, table: &MigrationTable) -> String {
+        let index = MigrationTable {
+            schema: table.schema.clone(),
+            name: format!("{}_name_idx", table.name),
+        };
+
+        format!(
+            "CREATE INDEX {} ON {} (name);",
+            index.qualified(),
+            table.name
+        )
+    }
+
+    // sqlite can't drop a constraint, so the table is rebuilt
+    fn drop_name_unique(&self, table: &MigrationTable) -> Vec<String> {
+        let rebuilt = MigrationTable {
+            schema: table.schema.clone(),
+            name: format!("{}_upgrade", table.name),
+        };
+        let columns = [
+            "id, name, valid, created_at, deleted_at, checksum, dirty".to_string(),
+            layout_columns()
+                .iter()
+                .map(|c| c.split(' ').next().unwrap_or(c))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ]
+        .join(", ");
+
+        vec![
+            self.create_migration_table(&rebuilt.qualified()),
+            format!(
+                "INSERT INTO {}({}) SELECT {} FROM {};",
+                rebuilt.qualified(),
+                columns,
+                columns,
+                table.qualified()
+            ),
+            format!("DROP TABLE {};", table.qualified()),
+            format!(
+                "ALTER TABLE {} RENAME TO {};",
+                rebuilt.qualified(),
+                table.name
+            ),
+        ]
+    }
+
+    fn column_exists(&self, table: &MigrationTable, column: &str) -> String {
+        let schema = table.schema.as_deref().unwrap_or("main");
+
+        format!(
+            "SELECT COUNT(*) AS count FROM pragma_table_info('{}', '{}') WHERE name = '{}';",
+            table.name, schema, column
+        )
+    }
+}
@@ -0,0 +1,28 @@
+/// A migration file baked into the binary by [`embed_migrations!`](crate::embed_migrations).
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedMigration {
+    pub file_name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Expands to a `&'static [EmbeddedMigration]` holding every file of a migrations folder.
+///
+/// The folder has to be registered in the crate's build script with
+/// [`build::embed_migrations`](crate::build::embed_migrations), which writes the list of files
+/// that this macro includes:
+///
+/// ```ignore
+/// // build.rs
+/// fn main() {
+///     migratour::build::embed_migrations("migrations").unwrap();
+/// }
+///
+/// // src/main.rs
+/// static MIGRATIONS: &[migratour::EmbeddedMigration] = migratour::embed_migrations!("migrations");
+/// ```
+#[macro_export]
+macro_rules! embed_migrations {
+    ($dir:literal) => {
+        include!(concat!(env!("OUT_DIR"), "/migratour/", $dir, ".rs"))
+    };
+}
@@ -0,0 +1,117 @@
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum MigratourError {
+    #[error("error connecting to the database: {0}")]
+    Connection(#[source] sqlx::Error),
+
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error("invalid arguments: {0}")]
+    InvalidArgument(String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("could not read {}: {source}", .path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("invalid migration file {file}: {reason}")]
+    FileParse { file: String, reason: String },
+
+    #[error("migration {name} is recorded as applied but its file is missing from the migrations folder")]
+    MissingFile { name: String },
+
+    #[error("migration {name} has no {direction} file")]
+    MissingSql {
+        name: String,
+        direction: &'static str,
+    },
+
+    #[error("migration {name} is out of order, its version {version} is older than the last applied migration {latest}")]
+    OutOfOrder {
+        version: u64,
+        name: String,
+        latest: u64,
+    },
+
+    #[error("there is no migration with version {0}")]
+    UnknownVersion(u64),
+
+    #[error("applied migrations were modified after being applied: {}", .migrations.join(", "))]
+    ChecksumMismatch { migrations: Vec<String> },
+
+    #[error("{requested} migrations to {direction} but only {available} can be")]
+    NotEnoughMigrations {
+        direction: &'static str,
+        requested: usize,
+        available: usize,
+    },
+
+    #[error("could not parse {file} at line {line}: {reason}")]
+    SqlParse {
+        file: String,
+        line: usize,
+        reason: String,
+    },
+
+    #[error("migration {migration} failed at statement {index} of {total} starting on line {line} of {file}: {source}")]
+    StatementFailed {
+        migration: String,
+        file: String,
+        index: usize,
+        total: usize,
+        line: usize,
+        #[source]
+        source: sqlx::Error,
+    },
+
+    #[error("migration {name} is dirty, it failed halfway and may be partly applied. Clean up the database by hand and mark it as applied or not with `migratour repair`")]
+    Dirty { name: String },
+
+    #[error("reverting migrations is not allowed here, allow_down is false")]
+    DownNotAllowed,
+
+    #[error("another migration is running, gave up waiting for the migration lock after {}s", .0.as_secs())]
+    LockTimeout(Duration),
+}
+
+impl MigratourError {
+    /// Exit code of the cli for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MigratourError::Config(_)
+            | MigratourError::InvalidArgument(_)
+            | MigratourError::DownNotAllowed => 2,
+            MigratourError::Connection(_) => 3,
+            MigratourError::Io(_)
+            | MigratourError::Read { .. }
+            | MigratourError::FileParse { .. }
+            | MigratourError::MissingFile { .. }
+            | MigratourError::MissingSql { .. }
+            | MigratourError::OutOfOrder { .. }
+            | MigratourError::UnknownVersion(_)
+            | MigratourError::NotEnoughMigrations { .. }
+            | MigratourError::SqlParse { .. } => 4,
+            MigratourError::ChecksumMismatch { .. } => 5,
+            MigratourError::Database(_) | MigratourError::StatementFailed { .. } => 6,
+            MigratourError::LockTimeout(_) => 7,
+            MigratourError::Dirty { .. } => 8,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, MigratourError>;
@@ -1,10 +1,11 @@
-use std::error::Error;
+use std::process;
 
 use migratour::cmd_run;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    cmd_run().await?;
-
-    Ok(())
+async fn main() {
+    if let Err(err) = cmd_run().await {
+        eprintln!("error: {}", err);
+        process::exit(err.exit_code());
+    }
 }
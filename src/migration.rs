@@ -0,0 +1,631 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use sha2::{Digest, Sha256};
+
+use crate::embed::EmbeddedMigration;
+use crate::error::{MigratourError, Result};
+use crate::splitter::{split_statements, Statement};
+use crate::DatabaseType;
+
+const NO_TRANSACTION_DIRECTIVE: &str = "migratour:no-transaction";
+
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub version: u64,
+    /// The version as written in the file names, leading zeros included.
+    pub version_label: String,
+    pub name: String,
+    pub up_file: Option<String>,
+    pub down_file: Option<String>,
+    pub up_sql: Option<String>,
+    pub down_sql: Option<String>,
+    pub checksum: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AppliedMigration {
+    pub name: String,
+    pub applied_at: String,
+    pub checksum: Option<String>,
+    pub dirty: bool,
+    pub applied_by: Option<RunInfo>,
+    pub duration_ms: Option<u64>,
+}
+
+/// Who ran a migration and with what, recorded with every apply and revert.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunInfo {
+    /// OS user running migratour.
+    pub user: String,
+    pub host: String,
+    pub migratour_version: String,
+    /// Environment of the config file the migration ran in, if any.
+    pub environment: Option<String>,
+}
+
+impl RunInfo {
+    pub fn current(environment: Option<String>) -> RunInfo {
+        // looking up the user and host can be slow, it is only done once per process
+        static WHOAMI: OnceLock<(String, String)> = OnceLock::new();
+        let (user, host) = WHOAMI.get_or_init(|| {
+            (
+                whoami::fallible::username().unwrap_or_default(),
+                whoami::fallible::hostname().unwrap_or_default(),
+            )
+        });
+
+        RunInfo {
+            user: user.clone(),
+            host: host.clone(),
+            migratour_version: env!("CARGO_PKG_VERSION").to_string(),
+            environment,
+        }
+    }
+}
+
+impl std::fmt::Display for RunInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.user, self.host)
+    }
+}
+
+// one row of the migrations table, a migration applied and possibly reverted later. rows
+// written before the run metadata was recorded have none
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub version: Option<u64>,
+    pub version_label: Option<String>,
+    pub name: String,
+    pub checksum: Option<String>,
+    pub dirty: bool,
+    pub applied_at: String,
+    pub applied_by: Option<RunInfo>,
+    pub apply_duration_ms: Option<u64>,
+    // applies and reverts are numbered in the order they happened, as timestamps can be equal
+    pub applied_seq: u64,
+    pub reverted_at: Option<String>,
+    pub reverted_by: Option<RunInfo>,
+    pub revert_duration_ms: Option<u64>,
+    pub reverted_seq: Option<u64>,
+    // reverted by `repair --not-applied` after failing, without running its down file
+    pub discarded: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    MissingFile,
+    Modified,
+    Dirty,
+}
+
+impl std::fmt::Display for MigrationState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            MigrationState::Applied => "applied",
+            MigrationState::Pending => "pending",
+            MigrationState::MissingFile => "missing-file",
+            MigrationState::Modified => "modified",
+            MigrationState::Dirty => "dirty",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: Option<u64>,
+    pub version_label: Option<String>,
+    pub name: String,
+    pub state: MigrationState,
+    pub applied_at: Option<String>,
+    pub applied_by: Option<RunInfo>,
+    pub duration_ms: Option<u64>,
+    pub has_down: bool,
+}
+
+impl Migration {
+    pub fn up_sql(&self) -> Result<&str> {
+        match &self.up_sql {
+            Some(sql) => Ok(sql),
+            None => Err(MigratourError::MissingSql {
+                name: self.name.clone(),
+                direction: "up",
+            }),
+        }
+    }
+
+    // whether the up or down file opts out of transactions with a
+    // "-- migratour:no-transaction" line in the comments at its top
+    pub fn no_transaction(&self, direction: &str) -> bool {
+        let sql = if direction == "up" {
+            &self.up_sql
+        } else {
+            &self.down_sql
+        };
+
+        sql.as_deref()
+            .unwrap_or_default()
+            .lines()
+            .map(|line| line.trim())
+            .take_while(|line| line.is_empty() || line.starts_with("--"))
+            .any(|line| line.trim_start_matches('-').trim() == NO_TRANSACTION_DIRECTIVE)
+    }
+
+    pub fn down_sql(&self) -> Result<&str> {
+        match &self.down_sql {
+            Some(sql) => Ok(sql),
+            None => Err(MigratourError::MissingSql {
+                name: self.name.clone(),
+                direction: "down",
+            }),
+        }
+    }
+}
+
+// file name and statements of the up or down sql of a migration
+pub fn migration_statements(
+    migration: &Migration,
+    direction: &'static str,
+    database: &DatabaseType,
+) -> Result<(String, Vec<Statement>)> {
+    let (sql, file) = if direction == "up" {
+        (migration.up_sql()?, &migration.up_file)
+    } else {
+        (migration.down_sql()?, &migration.down_file)
+    };
+    let file = file
+        .clone()
+        .unwrap_or_else(|| format!("{}.{}.sql", migration.name, direction));
+
+    let statements = split_statements(sql, database).map_err(|e| MigratourError::SqlParse {
+        file: file.clone(),
+        line: e.line,
+        reason: e.reason,
+    })?;
+
+    Ok((file, statements))
+}
+
+// sha-256 of the up file with line endings and trailing whitespace normalized, so that
+// re-saving a file with a different editor doesn't count as modifying it
+pub fn checksum(sql: &str) -> String {
+    let normalized: Vec<&str> = sql.trim_end().lines().map(|line| line.trim_end()).collect();
+
+    hex::encode(Sha256::digest(normalized.join("\n").as_bytes()))
+}
+
+// splits "0001_users_table.up.sql" into (1, "0001", "users_table", "up")
+fn parse_file_name(file_name: &str) -> Result<(u64, String, String, String)> {
+    let invalid = || MigratourError::FileParse {
+        file: file_name.to_string(),
+        reason: "expected a name like 0001_name.up.sql or 0001_name.down.sql".to_string(),
+    };
+
+    let stem = file_name.strip_suffix(".sql").ok_or_else(invalid)?;
+    let (stem, direction) = stem.rsplit_once('.').ok_or_else(invalid)?;
+    let direction = direction.to_lowercase();
+    if direction != "up" && direction != "down" {
+        return Err(invalid());
+    }
+
+    let (label, name) = stem.split_once('_').ok_or_else(invalid)?;
+    if name.is_empty() || label.is_empty() || !label.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let version = label.parse::<u64>().map_err(|_| invalid())?;
+
+    Ok((version, label.to_string(), name.to_string(), direction))
+}
+
+pub fn parse_migration_files(file_names: Vec<String>) -> Result<Vec<Migration>> {
+    let mut migrations: BTreeMap<u64, Migration> = BTreeMap::new();
+
+    for file_name in file_names {
+        if file_name.starts_with('.') {
+            continue;
+        }
+
+        let (version, label, name, direction) = parse_file_name(&file_name)?;
+
+        let mg = migrations.entry(version).or_insert_with(|| Migration {
+            version,
+            version_label: label,
+            name: name.clone(),
+            up_file: None,
+            down_file: None,
+            up_sql: None,
+            down_sql: None,
+            checksum: None,
+        });
+
+        if mg.name != name {
+            return Err(MigratourError::FileParse {
+                file: file_name,
+                reason: format!("migration {} has the same version {}", mg.name, version),
+            });
+        }
+
+        let slot = if direction == "up" {
+            &mut mg.up_file
+        } else {
+            &mut mg.down_file
+        };
+        if slot.is_some() {
+            return Err(MigratourError::FileParse {
+                file: file_name,
+                reason: format!("duplicate {} file for migration {}", direction, name),
+            });
+        }
+        *slot = Some(file_name);
+    }
+
+    let mut seen_names: HashSet<&String> = HashSet::new();
+    for mg in migrations.values() {
+        if !seen_names.insert(&mg.name) {
+            return Err(MigratourError::FileParse {
+                file: mg
+                    .up_file
+                    .clone()
+                    .or(mg.down_file.clone())
+                    .unwrap_or_default(),
+                reason: format!("more than one migration is named {}", mg.name),
+            });
+        }
+    }
+
+    Ok(migrations.into_values().collect())
+}
+
+pub fn read_migration_files(dir: &Path) -> Result<Vec<String>> {
+    let entries = fs::read_dir(dir).map_err(|e| read_error(dir, e))?;
+    let file_names: Vec<String> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.is_file() {
+                path.file_name()?.to_str().map(|s| s.to_owned())
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    Ok(file_names)
+}
+
+fn read_error(path: &Path, source: io::Error) -> MigratourError {
+    MigratourError::Read {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+fn read_sql<F>(mut migrations: Vec<Migration>, read: F) -> Result<Vec<Migration>>
+where
+    F: Fn(&str) -> Result<String>,
+{
+    for mg in migrations.iter_mut() {
+        if let Some(up_file) = &mg.up_file {
+            let up_sql = read(up_file)?;
+            mg.checksum = Some(checksum(&up_sql));
+            mg.up_sql = Some(up_sql);
+        }
+        if let Some(down_file) = &mg.down_file {
+            mg.down_sql = Some(read(down_file)?);
+        }
+    }
+
+    Ok(migrations)
+}
+
+// parses the migrations folder and reads the sql of every migration
+pub fn load_migrations(dir: &Path) -> Result<Vec<Migration>> {
+    let migrations = parse_migration_files(read_migration_files(dir)?)?;
+
+    read_sql(migrations, |file| {
+        let path = dir.join(file);
+        fs::read_to_string(&path).map_err(|e| read_error(&path, e))
+    })
+}
+
+// same as load_migrations, for migration files baked into the binary
+pub fn load_embedded_migrations(files: &[EmbeddedMigration]) -> Result<Vec<Migration>> {
+    let migrations =
+        parse_migration_files(files.iter().map(|f| f.file_name.to_string()).collect())?;
+
+    read_sql(migrations, |file| {
+        Ok(files
+            .iter()
+            .find(|f| f.file_name == file)
+            .map(|f| f.sql.to_string())
+            .unwrap_or_default())
+    })
+}
+
+// migrations recorded in the database, in version order
+pub fn applied_migrations<'a>(
+    migrations: &'a [Migration],
+    applied_names: &[String],
+) -> Result<Vec<&'a Migration>> {
+    for name in applied_names {
+        if !migrations.iter().any(|mg| &mg.name == name) {
+            return Err(MigratourError::MissingFile { name: name.clone() });
+        }
+    }
+
+    Ok(migrations
+        .iter()
+        .filter(|mg| applied_names.contains(&mg.name))
+        .collect())
+}
+
+// migrations not yet recorded in the database, in version order
+pub fn pending_migrations<'a>(
+    migrations: &'a [Migration],
+    applied_names: &[String],
+) -> Result<Vec<&'a Migration>> {
+    let applied = applied_migrations(migrations, applied_names)?;
+    let latest_applied = applied.last().map(|mg| mg.version);
+
+    let pending: Vec<&Migration> = migrations
+        .iter()
+        .filter(|mg| !applied_names.contains(&mg.name))
+        .collect();
+
+    if let Some(latest) = latest_applied {
+        if let Some(mg) = pending.iter().find(|mg| mg.version < latest) {
+            return Err(MigratourError::OutOfOrder {
+                version: mg.version,
+                name: mg.name.clone(),
+                latest,
+            });
+        }
+    }
+
+    Ok(pending)
+}
+
+// one entry per migration file plus one per recorded migration whose file is gone
+pub fn migration_status(
+    migrations: &[Migration],
+    applied: &[AppliedMigration],
+) -> Vec<MigrationStatus> {
+    let mut statuses: Vec<MigrationStatus> = migrations
+        .iter()
+        .map(|mg| {
+            let record = applied.iter().find(|a| a.name == mg.name);
+            MigrationStatus {
+                version: Some(mg.version),
+                version_label: Some(mg.version_label.clone()),
+                name: mg.name.clone(),
+                state: match record {
+                    Some(a) if a.dirty => MigrationState::Dirty,
+                    Some(a) if is_modified(mg, a) => MigrationState::Modified,
+                    Some(_) => MigrationState::Applied,
+                    None => MigrationState::Pending,
+                },
+                applied_at: record.map(|a| a.applied_at.clone()),
+                applied_by: record.and_then(|a| a.applied_by.clone()),
+                duration_ms: record.and_then(|a| a.duration_ms),
+                has_down: mg.down_sql.is_some(),
+            }
+        })
+        .collect();
+
+    for a in applied {
+        if !migrations.iter().any(|mg| mg.name == a.name) {
+            statuses.push(MigrationStatus {
+                version: None,
+                version_label: None,
+                name: a.name.clone(),
+                state: if a.dirty {
+                    MigrationState::Dirty
+                } else {
+                    MigrationState::MissingFile
+                },
+                applied_at: Some(a.applied_at.clone()),
+                applied_by: a.applied_by.clone(),
+                duration_ms: a.duration_ms,
+                has_down: false,
+            });
+        }
+    }
+
+    statuses
+}
+
+// rows recorded before checksums were stored have nothing to compare against
+fn is_modified(mg: &Migration, applied: &AppliedMigration) -> bool {
+    match (&mg.checksum, &applied.checksum) {
+        (Some(file_checksum), Some(recorded)) => file_checksum != recorded,
+        _ => false,
+    }
+}
+
+// refuses to run while a migration failed halfway, until it is repaired
+pub fn check_not_dirty(applied: &[AppliedMigration]) -> Result<()> {
+    if let Some(a) = applied.iter().find(|a| a.dirty) {
+        return Err(MigratourError::Dirty {
+            name: a.name.clone(),
+        });
+    }
+
+    Ok(())
+}
+
+pub fn verify_checksums(migrations: &[Migration], applied: &[AppliedMigration]) -> Result<()> {
+    let modified: Vec<String> = migrations
+        .iter()
+        .filter(|mg| {
+            applied
+                .iter()
+                .any(|a| a.name == mg.name && is_modified(mg, a))
+        })
+        .map(|mg| format!("{}_{}", mg.version_label, mg.name))
+        .collect();
+
+    if !modified.is_empty() {
+        return Err(MigratourError::ChecksumMismatch {
+            migrations: modified,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn migrations(names: &[&str]) -> Vec<Migration> {
+        parse_migration_files(files(names)).unwrap()
+    }
+
+    fn names(migrations: &[&Migration]) -> Vec<String> {
+        migrations.iter().map(|mg| mg.name.clone()).collect()
+    }
+
+    fn with_up_sql(sql: &str) -> Migration {
+        let mut mg = migrations(&["0001_users.up.sql"]).remove(0);
+        mg.up_sql = Some(sql.to_string());
+        mg
+    }
+
+    #[test]
+    fn parses_file_names() {
+        let migrations = migrations(&[
+            "0002_posts.up.sql",
+            "0001_users_table.up.sql",
+            "0001_users_table.down.sql",
+            ".gitkeep",
+            ".0003_hidden.up.sql",
+        ]);
+
+        assert_eq!(migrations.len(), 2);
+        assert_eq!(migrations[0].version, 1);
+        assert_eq!(migrations[0].version_label, "0001");
+        assert_eq!(migrations[0].name, "users_table");
+        assert_eq!(
+            migrations[0].down_file.as_deref(),
+            Some("0001_users_table.down.sql")
+        );
+        assert_eq!(migrations[1].name, "posts");
+        assert!(migrations[1].down_file.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_file_names() {
+        for name in [
+            "users.up.sql",
+            "0001_users.sql",
+            "0001_users.sideways.sql",
+            "0001_.up.sql",
+            "v1_users.up.sql",
+            "README.md",
+        ] {
+            assert!(
+                matches!(
+                    parse_migration_files(files(&[name])),
+                    Err(MigratourError::FileParse { .. })
+                ),
+                "{} was accepted",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        for names in [
+            &["0001_users.up.sql", "0001_accounts.up.sql"][..],
+            &["0001_users.up.sql", "01_users.up.sql"],
+            &["0001_users.up.sql", "0002_users.up.sql"],
+        ] {
+            assert!(
+                matches!(
+                    parse_migration_files(files(names)),
+                    Err(MigratourError::FileParse { .. })
+                ),
+                "{:?} was accepted",
+                names
+            );
+        }
+    }
+
+    #[test]
+    fn applied_and_pending() {
+        let migrations =
+            migrations(&["0001_users.up.sql", "0002_posts.up.sql", "0003_tags.up.sql"]);
+        let applied_names = files(&["users", "posts"]);
+
+        assert_eq!(
+            names(&applied_migrations(&migrations, &applied_names).unwrap()),
+            vec!["users", "posts"]
+        );
+        assert_eq!(
+            names(&pending_migrations(&migrations, &applied_names).unwrap()),
+            vec!["tags"]
+        );
+    }
+
+    #[test]
+    fn missing_file() {
+        let migrations = migrations(&["0001_users.up.sql"]);
+        let applied_names = files(&["users", "posts"]);
+
+        match applied_migrations(&migrations, &applied_names) {
+            Err(MigratourError::MissingFile { name }) => assert_eq!(name, "posts"),
+            result => panic!("expected a missing file, got {:?}", result),
+        }
+        assert!(matches!(
+            pending_migrations(&migrations, &applied_names),
+            Err(MigratourError::MissingFile { .. })
+        ));
+    }
+
+    #[test]
+    fn out_of_order() {
+        let migrations =
+            migrations(&["0001_users.up.sql", "0002_posts.up.sql", "0003_tags.up.sql"]);
+
+        match pending_migrations(&migrations, &files(&["users", "tags"])) {
+            Err(MigratourError::OutOfOrder {
+                version, latest, ..
+            }) => assert_eq!((version, latest), (2, 3)),
+            result => panic!("expected an out of order migration, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn no_transaction_directive() {
+        assert!(
+            with_up_sql("-- migratour:no-transaction\nCREATE INDEX a ON t (a);")
+                .no_transaction("up")
+        );
+        assert!(
+            with_up_sql("\n-- concurrently\n--migratour:no-transaction\nSELECT 1;")
+                .no_transaction("up")
+        );
+        assert!(!with_up_sql("SELECT 1;\n-- migratour:no-transaction").no_transaction("up"));
+        assert!(!with_up_sql("-- no transaction here\nSELECT 1;").no_transaction("up"));
+        assert!(!with_up_sql("-- migratour:no-transaction").no_transaction("down"));
+    }
+
+    #[test]
+    fn checksums_ignore_line_endings() {
+        assert_eq!(
+            checksum("SELECT 1;\nSELECT 2;\n"),
+            checksum("SELECT 1;  \r\nSELECT 2;\r\n\r\n")
+        );
+        assert_ne!(checksum("SELECT 1;"), checksum("SELECT 2;"));
+    }
+}
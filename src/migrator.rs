@@ -0,0 +1,717 @@
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use sqlx::{MySqlPool, PgPool, SqlitePool};
+
+use crate::db::{Db, DbExe, MySqlDb, PostgresDb, SqliteDb};
+use crate::dialect::{MigrationTable, MIGRATION_TABLE_VERSION};
+use crate::embed::EmbeddedMigration;
+use crate::error::{MigratourError, Result};
+use crate::migration::{
+    applied_migrations, check_not_dirty, load_embedded_migrations, load_migrations,
+    migration_statements, migration_status, pending_migrations, verify_checksums, HistoryEntry,
+    Migration, MigrationStatus, RunInfo,
+};
+use crate::splitter::Statement;
+use crate::url::DatabaseUrl;
+use crate::DatabaseType;
+
+pub const DEFAULT_MIGRATIONS_DIR: &str = "./migrations";
+pub const DEFAULT_TABLE_NAME: &str = "db_migrations";
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+// the migrations run on one connection, mysql marks them dirty from a second one
+const MYSQL_MIN_CONNECTIONS: u32 = 2;
+
+/// An existing sqlx pool for the migrator to run on.
+pub enum DbPool {
+    Postgres(PgPool),
+    MySql(MySqlPool),
+    Sqlite(SqlitePool),
+}
+
+impl From<PgPool> for DbPool {
+    fn from(pool: PgPool) -> Self {
+        DbPool::Postgres(pool)
+    }
+}
+
+impl From<MySqlPool> for DbPool {
+    fn from(pool: MySqlPool) -> Self {
+        DbPool::MySql(pool)
+    }
+}
+
+impl From<SqlitePool> for DbPool {
+    fn from(pool: SqlitePool) -> Self {
+        DbPool::Sqlite(pool)
+    }
+}
+
+enum MigrationSource {
+    Dir(PathBuf),
+    Embedded(&'static [EmbeddedMigration]),
+}
+
+enum Connection {
+    Url(DatabaseType, DatabaseUrl),
+    Pool(DbPool),
+}
+
+/// A migration applied or reverted by the [`Migrator`].
+#[derive(Debug, Clone)]
+pub struct MigrationSummary {
+    pub version: u64,
+    pub name: String,
+}
+
+impl From<&Migration> for MigrationSummary {
+    fn from(mg: &Migration) -> Self {
+        MigrationSummary {
+            version: mg.version,
+            name: mg.name.clone(),
+        }
+    }
+}
+
+/// How far `up` or `down` goes.
+#[derive(Debug, Clone, Copy)]
+pub enum Target {
+    /// Every pending migration going up, every applied one going down.
+    All,
+    /// This many migrations.
+    Count(usize),
+    /// Up to and including this version going up, down to but not including it going down.
+    /// Version 0 is the state before the first migration.
+    Version(u64),
+}
+
+/// How migrations are wrapped in transactions. Migrations whose file starts with a
+/// `-- migratour:no-transaction` comment always run outside of one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransactionMode {
+    /// One transaction for the whole batch.
+    #[default]
+    All,
+    /// One transaction per migration.
+    PerMigration,
+    /// No transactions.
+    None,
+}
+
+// a run of migrations sharing a transaction, or one migration run outside of any
+enum Batch<'a> {
+    Transaction(Vec<&'a Migration>),
+    NoTransaction(&'a Migration),
+}
+
+fn batches<'a>(
+    migrations: Vec<&'a Migration>,
+    direction: &str,
+    mode: TransactionMode,
+) -> Vec<Batch<'a>> {
+    let mut batches = Vec::new();
+    let mut current = Vec::new();
+
+    for mg in migrations {
+        if mode == TransactionMode::None || mg.no_transaction(direction) {
+            if !current.is_empty() {
+                batches.push(Batch::Transaction(std::mem::take(&mut current)));
+            }
+            batches.push(Batch::NoTransaction(mg));
+        } else if mode == TransactionMode::PerMigration {
+            batches.push(Batch::Transaction(vec![mg]));
+        } else {
+            current.push(mg);
+        }
+    }
+    if !current.is_empty() {
+        batches.push(Batch::Transaction(current));
+    }
+
+    batches
+}
+
+/// Migrations applied or reverted by [`Migrator::goto`].
+#[derive(Debug, Clone)]
+pub enum GotoResult {
+    Applied(Vec<MigrationSummary>),
+    Reverted(Vec<MigrationSummary>),
+}
+
+/// A migration `up` would apply, with its statements as they are sent to the database.
+#[derive(Debug, Clone)]
+pub struct PlannedMigration {
+    pub version: u64,
+    pub version_label: String,
+    pub name: String,
+    pub file: String,
+    pub statements: Vec<Statement>,
+    /// Runs outside of a transaction, so the dry run doesn't run it.
+    pub no_transaction: bool,
+    /// Whether the dry run ran it. It stops at the first migration running outside of a
+    /// transaction, as the ones after it may depend on it, and runs nothing on MySQL.
+    pub run: bool,
+}
+
+/// Configures and connects a [`Migrator`].
+pub struct MigratorBuilder {
+    source: MigrationSource,
+    table: String,
+    schema: Option<String>,
+    connection: Option<Connection>,
+    lock_timeout: Option<Duration>,
+    transaction_mode: TransactionMode,
+    environment: Option<String>,
+    allow_down: bool,
+}
+
+impl MigratorBuilder {
+    /// Folder holding the `.up.sql` and `.down.sql` files, `./migrations` by default.
+    pub fn migrations_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.source = MigrationSource::Dir(dir.into());
+        self
+    }
+
+    /// Uses migrations baked into the binary with
+    /// [`embed_migrations!`](crate::embed_migrations) instead of a folder.
+    pub fn embedded_migrations(mut self, migrations: &'static [EmbeddedMigration]) -> Self {
+        self.source = MigrationSource::Embedded(migrations);
+        self
+    }
+
+    /// Table recording the applied migrations, `db_migrations` by default.
+    pub fn table_name(mut self, table: impl Into<String>) -> Self {
+        self.table = table.into();
+        self
+    }
+
+    /// Schema holding the migrations table, the current schema by default. On MySQL it is a
+    /// database, on SQLite an attached database. On PostgreSQL the schema is created when it
+    /// doesn't exist.
+    pub fn schema_name(mut self, schema: impl Into<String>) -> Self {
+        self.schema = Some(schema.into());
+        self
+    }
+
+    /// Connects to the database at `url` when the migrator is built.
+    pub fn database_url(mut self, database: DatabaseType, url: impl Into<DatabaseUrl>) -> Self {
+        self.connection = Some(Connection::Url(database, url.into()));
+        self
+    }
+
+    /// Runs the migrations on an existing pool. While the migration lock is held they run on
+    /// the connection holding it, so one connection is enough on PostgreSQL and SQLite. MySQL
+    /// needs a second one, to mark a migration dirty outside of its transaction.
+    pub fn pool(mut self, pool: impl Into<DbPool>) -> Self {
+        self.connection = Some(Connection::Pool(pool.into()));
+        self
+    }
+
+    /// How long `up` and `down` wait for the migration lock, `None` runs them without it.
+    /// Creating or upgrading the migrations table always takes the lock, waiting
+    /// [`DEFAULT_LOCK_TIMEOUT`] for it when this is `None`.
+    pub fn lock_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// How migrations are wrapped in transactions, one transaction for all of them by default.
+    pub fn transaction_mode(mut self, mode: TransactionMode) -> Self {
+        self.transaction_mode = mode;
+        self
+    }
+
+    /// Environment name recorded with every migration applied or reverted, none by default.
+    pub fn environment(mut self, environment: impl Into<String>) -> Self {
+        self.environment = Some(environment.into());
+        self
+    }
+
+    /// Whether migrations can be reverted, `true` by default. With `false`, `down` and `goto`
+    /// to an older version fail, to protect environments like production.
+    pub fn allow_down(mut self, allow: bool) -> Self {
+        self.allow_down = allow;
+        self
+    }
+
+    /// Connects to the database and creates the migrations table when it doesn't exist.
+    pub async fn build(self) -> Result<Migrator> {
+        if !is_identifier(&self.table) {
+            return Err(MigratourError::Config(format!(
+                "invalid migrations table name {}",
+                self.table
+            )));
+        }
+        if let Some(schema) = self.schema.as_deref().filter(|s| !is_identifier(s)) {
+            return Err(MigratourError::Config(format!(
+                "invalid migrations schema name {}",
+                schema
+            )));
+        }
+        if let Some(Connection::Pool(DbPool::MySql(pool))) = &self.connection {
+            if pool.options().get_max_connections() < MYSQL_MIN_CONNECTIONS {
+                return Err(MigratourError::Config(format!(
+                    "the mysql pool needs at least {} connections",
+                    MYSQL_MIN_CONNECTIONS
+                )));
+            }
+        }
+        let table = MigrationTable {
+            schema: self.schema,
+            name: self.table,
+        };
+        let run = RunInfo::current(self.environment);
+
+        // drivers can quote the url in their errors
+        let url = match &self.connection {
+            Some(Connection::Url(_, url)) => Some(url.clone()),
+            _ => None,
+        };
+        let redact = |e: MigratourError| match &url {
+            Some(url) => url.redact_error(e),
+            None => e,
+        };
+
+        let db = match self.connection {
+            None => {
+                return Err(MigratourError::Config(
+                    "no database url or pool given to the migrator".to_string(),
+                ))
+            }
+            Some(Connection::Url(DatabaseType::Postgres, url)) => DbExe::PgExe(
+                PostgresDb::new_connection(url.expose().to_string(), table, run)
+                    .await
+                    .map_err(redact)?,
+            ),
+            Some(Connection::Url(DatabaseType::MySql, url)) => DbExe::MySqlExe(
+                MySqlDb::new_connection(url.expose().to_string(), table, run)
+                    .await
+                    .map_err(redact)?,
+            ),
+            Some(Connection::Url(DatabaseType::Sqlite, url)) => DbExe::SqliteExe(
+                SqliteDb::new_connection(url.expose().to_string(), table, run)
+                    .await
+                    .map_err(redact)?,
+            ),
+            Some(Connection::Pool(DbPool::Postgres(pool))) => {
+                DbExe::PgExe(PostgresDb::from_pool(pool, table, run))
+            }
+            Some(Connection::Pool(DbPool::MySql(pool))) => {
+                DbExe::MySqlExe(MySqlDb::from_pool(pool, table, run))
+            }
+            Some(Connection::Pool(DbPool::Sqlite(pool))) => {
+                DbExe::SqliteExe(SqliteDb::from_pool(pool, table, run))
+            }
+        };
+
+        db.ping_db().await.map_err(|e| match e {
+            MigratourError::Database(e) => redact(MigratourError::Connection(e)),
+            e => e,
+        })?;
+
+        // several replicas starting at once would race to create or upgrade the table, so
+        // that always happens under the lock, even for migrators running without it otherwise
+        if migration_table_outdated(&db).await? {
+            db.lock(self.lock_timeout.unwrap_or(DEFAULT_LOCK_TIMEOUT))
+                .await?;
+            let result = prepare_migration_table(&db).await;
+            let unlocked = db.unlock().await;
+            result?;
+            unlocked?;
+        }
+
+        Ok(Migrator {
+            db,
+            source: self.source,
+            lock_timeout: self.lock_timeout,
+            transaction_mode: self.transaction_mode,
+            allow_down: self.allow_down,
+        })
+    }
+}
+
+// whether the migrations table is missing or has another layout than the current one
+async fn migration_table_outdated(db: &DbExe) -> Result<bool> {
+    if !db.table_exists().await? {
+        return Ok(true);
+    }
+
+    Ok(db.migration_table_version().await? != MIGRATION_TABLE_VERSION)
+}
+
+// creates the migrations table, or upgrades it when an older migratour created it. another
+// process may have done it while this one waited for the lock, so it checks again
+async fn prepare_migration_table(db: &DbExe) -> Result<()> {
+    if !db.table_exists().await? {
+        return db.create_migration_table().await;
+    }
+
+    let version = db.migration_table_version().await?;
+    if version > MIGRATION_TABLE_VERSION {
+        return Err(MigratourError::Config(format!(
+            "the migrations table has layout version {} but this migratour only knows up to {}, please upgrade migratour",
+            version, MIGRATION_TABLE_VERSION
+        )));
+    }
+    if version < MIGRATION_TABLE_VERSION {
+        db.upgrade_migration_table(version).await?;
+    }
+
+    Ok(())
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Applies and reverts the migrations of a folder, or embedded ones, against one database.
+pub struct Migrator {
+    db: DbExe,
+    source: MigrationSource,
+    lock_timeout: Option<Duration>,
+    transaction_mode: TransactionMode,
+    allow_down: bool,
+}
+
+impl Migrator {
+    pub fn builder() -> MigratorBuilder {
+        MigratorBuilder {
+            source: MigrationSource::Dir(PathBuf::from(DEFAULT_MIGRATIONS_DIR)),
+            table: DEFAULT_TABLE_NAME.to_string(),
+            schema: None,
+            connection: None,
+            lock_timeout: Some(DEFAULT_LOCK_TIMEOUT),
+            transaction_mode: TransactionMode::default(),
+            environment: None,
+            allow_down: true,
+        }
+    }
+
+    pub fn database(&self) -> DatabaseType {
+        self.db.database()
+    }
+
+    pub fn migrations(&self) -> Result<Vec<Migration>> {
+        match &self.source {
+            MigrationSource::Dir(dir) => load_migrations(dir),
+            MigrationSource::Embedded(files) => load_embedded_migrations(files),
+        }
+    }
+
+    /// Applies `num` pending migrations, or all of them when `num` is `None`.
+    pub async fn up(&self, num: Option<usize>) -> Result<Vec<MigrationSummary>> {
+        let applied = self
+            .locked(self.run_up(num.map_or(Target::All, Target::Count), false))
+            .await?;
+
+        Ok(applied.iter().map(|mg| mg.into()).collect())
+    }
+
+    /// Applies the pending migrations up to and including `version`.
+    pub async fn up_to(&self, version: u64) -> Result<Vec<MigrationSummary>> {
+        let applied = self
+            .locked(self.run_up(Target::Version(version), false))
+            .await?;
+
+        Ok(applied.iter().map(|mg| mg.into()).collect())
+    }
+
+    /// Works out the migrations `up` would apply and splits them into statements. On
+    /// PostgreSQL and SQLite they are also run inside a transaction that is rolled back, so
+    /// syntax and constraint errors surface without changing the database, up to the first
+    /// one that can't run inside a transaction. MySQL commits DDL implicitly, so nothing is
+    /// run there.
+    pub async fn up_dry_run(&self, target: Target) -> Result<Vec<PlannedMigration>> {
+        let to_apply = self.locked(self.run_up(target, true)).await?;
+
+        let database = self.db.database();
+        let mut run = !matches!(database, DatabaseType::MySql);
+        let mut plan = Vec::new();
+        for mg in to_apply {
+            let (file, statements) = migration_statements(&mg, "up", &database)?;
+            let no_transaction = mg.no_transaction("up");
+            run &= !no_transaction;
+            plan.push(PlannedMigration {
+                no_transaction,
+                run,
+                version: mg.version,
+                version_label: mg.version_label,
+                name: mg.name,
+                file,
+                statements,
+            });
+        }
+
+        Ok(plan)
+    }
+
+    /// Reverts the last `num` applied migrations, newest first.
+    pub async fn down(&self, num: usize) -> Result<Vec<MigrationSummary>> {
+        self.locked(self.run_down(Target::Count(num))).await
+    }
+
+    /// Reverts the applied migrations newer than `version`, newest first.
+    pub async fn down_to(&self, version: u64) -> Result<Vec<MigrationSummary>> {
+        self.locked(self.run_down(Target::Version(version))).await
+    }
+
+    /// Applies or reverts migrations until `version` is the last applied one.
+    pub async fn goto(&self, version: u64) -> Result<GotoResult> {
+        self.locked(self.run_goto(version)).await
+    }
+
+    /// One entry per migration file, plus one per applied migration whose file is missing.
+    pub async fn status(&self) -> Result<Vec<MigrationStatus>> {
+        let applied = self.db.get_applied_migrations().await?;
+
+        let migrations = self.migrations()?;
+
+        Ok(migration_status(&migrations, &applied))
+    }
+
+    /// Every migration applied so far, including the reverted ones, oldest first.
+    pub async fn history(&self) -> Result<Vec<HistoryEntry>> {
+        let mut history = self.db.get_migration_history().await?;
+
+        let migrations = self.migrations()?;
+        for entry in history.iter_mut() {
+            if let Some(mg) = migrations.iter().find(|mg| mg.name == entry.name) {
+                entry.version = Some(mg.version);
+                entry.version_label = Some(mg.version_label.clone());
+            }
+        }
+
+        Ok(history)
+    }
+
+    /// Checks that every applied migration still has a file with the recorded checksum,
+    /// returning the number of applied migrations.
+    pub async fn verify(&self) -> Result<usize> {
+        let applied = self.db.get_applied_migrations().await?;
+
+        let migrations = self.migrations()?;
+
+        let applied_names: Vec<String> = applied.iter().map(|a| a.name.clone()).collect();
+        applied_migrations(&migrations, &applied_names)?;
+
+        verify_checksums(&migrations, &applied)?;
+
+        Ok(applied.len())
+    }
+
+    /// Resolves a migration marked dirty after failing halfway, once the database was cleaned
+    /// up by hand: with `applied` it is kept as applied, otherwise its record is removed.
+    pub async fn repair(&self, version: u64, applied: bool) -> Result<MigrationSummary> {
+        self.locked(self.run_repair(version, applied)).await
+    }
+
+    /// Name of the most recently applied migration, `None` when no migration is applied.
+    pub async fn last(&self) -> Result<Option<String>> {
+        self.db.get_last_migration().await
+    }
+
+    /// Number of applied migrations.
+    pub async fn count(&self) -> Result<usize> {
+        self.db.get_migration_table_count().await
+    }
+
+    async fn lock(&self) -> Result<()> {
+        if let Some(timeout) = self.lock_timeout {
+            self.db.lock(timeout).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn unlock(&self) -> Result<()> {
+        if self.lock_timeout.is_some() {
+            self.db.unlock().await?;
+        }
+
+        Ok(())
+    }
+
+    // runs under the migration lock. when the run fails, unlocking likely fails for the same
+    // reason (a dropped connection), so the error of the run is the one returned
+    async fn locked<T>(&self, run: impl Future<Output = Result<T>>) -> Result<T> {
+        self.lock().await?;
+        let result = run.await;
+        let unlocked = self.unlock().await;
+
+        let value = result?;
+        unlocked?;
+
+        Ok(value)
+    }
+
+    async fn run_repair(&self, version: u64, applied: bool) -> Result<MigrationSummary> {
+        let records = self.db.get_applied_migrations().await?;
+        let migrations = self.migrations()?;
+
+        let mg = migrations
+            .iter()
+            .find(|mg| mg.version == version)
+            .ok_or(MigratourError::UnknownVersion(version))?;
+
+        if !records.iter().any(|a| a.name == mg.name && a.dirty) {
+            return Err(MigratourError::InvalidArgument(format!(
+                "migration {}_{} is not dirty",
+                mg.version_label, mg.name
+            )));
+        }
+
+        self.db.resolve_dirty(&mg.name, applied).await?;
+
+        Ok(mg.into())
+    }
+
+    async fn run_goto(&self, version: u64) -> Result<GotoResult> {
+        let applied_names: Vec<String> = self
+            .db
+            .get_applied_migrations()
+            .await?
+            .into_iter()
+            .map(|a| a.name)
+            .collect();
+
+        let migrations = self.migrations()?;
+        check_target(&migrations, version)?;
+
+        let applied = applied_migrations(&migrations, &applied_names)?;
+        let latest = applied.last().map_or(0, |mg| mg.version);
+
+        if version < latest {
+            Ok(GotoResult::Reverted(
+                self.run_down(Target::Version(version)).await?,
+            ))
+        } else {
+            let migrations = self.run_up(Target::Version(version), false).await?;
+            Ok(GotoResult::Applied(
+                migrations.iter().map(|mg| mg.into()).collect(),
+            ))
+        }
+    }
+
+    async fn run_up(&self, target: Target, dry_run: bool) -> Result<Vec<Migration>> {
+        let applied = self.db.get_applied_migrations().await?;
+        check_not_dirty(&applied)?;
+        let applied_names: Vec<String> = applied.iter().map(|a| a.name.clone()).collect();
+
+        let migrations = self.migrations()?;
+
+        verify_checksums(&migrations, &applied)?;
+
+        let unapplied_migrations = pending_migrations(&migrations, &applied_names)?;
+
+        let migrations_to_apply = match target {
+            Target::All => unapplied_migrations.len(),
+            Target::Count(num) => num,
+            Target::Version(version) => {
+                check_target(&migrations, version)?;
+                unapplied_migrations
+                    .iter()
+                    .filter(|mg| mg.version <= version)
+                    .count()
+            }
+        };
+        if unapplied_migrations.len() < migrations_to_apply {
+            return Err(MigratourError::NotEnoughMigrations {
+                direction: "apply",
+                requested: migrations_to_apply,
+                available: unapplied_migrations.len(),
+            });
+        }
+
+        let to_apply: Vec<&Migration> = unapplied_migrations
+            .into_iter()
+            .take(migrations_to_apply)
+            .collect();
+        let applied = to_apply.iter().map(|mg| (*mg).clone()).collect();
+
+        if dry_run {
+            // migrations that can't run inside a transaction can't be rolled back either, and
+            // the ones after them may need what they create, so the run stops at the first one
+            let to_run = to_apply
+                .into_iter()
+                .take_while(|mg| !mg.no_transaction("up"))
+                .collect();
+            self.db.up_migration_transaction(to_run, true).await?;
+
+            return Ok(applied);
+        }
+
+        for batch in batches(to_apply, "up", self.transaction_mode) {
+            match batch {
+                Batch::Transaction(mgs) => self.db.up_migration_transaction(mgs, false).await?,
+                Batch::NoTransaction(mg) => self.db.up_migration_no_transaction(mg).await?,
+            }
+        }
+
+        Ok(applied)
+    }
+
+    async fn run_down(&self, target: Target) -> Result<Vec<MigrationSummary>> {
+        if !self.allow_down {
+            return Err(MigratourError::DownNotAllowed);
+        }
+
+        let records = self.db.get_applied_migrations().await?;
+        check_not_dirty(&records)?;
+        let applied_names: Vec<String> = records.into_iter().map(|a| a.name).collect();
+
+        let migrations = self.migrations()?;
+
+        let applied = applied_migrations(&migrations, &applied_names)?;
+
+        let num = match target {
+            Target::All => applied.len(),
+            Target::Count(num) => num,
+            Target::Version(version) => {
+                check_target(&migrations, version)?;
+                applied.iter().filter(|mg| mg.version > version).count()
+            }
+        };
+        if applied.len() < num {
+            return Err(MigratourError::NotEnoughMigrations {
+                direction: "revert",
+                requested: num,
+                available: applied.len(),
+            });
+        }
+
+        let down_migrations: Vec<&Migration> = applied.into_iter().rev().take(num).collect();
+
+        for mg in &down_migrations {
+            mg.down_sql()?;
+        }
+        let summaries = down_migrations.iter().map(|mg| (*mg).into()).collect();
+
+        for batch in batches(down_migrations, "down", self.transaction_mode) {
+            match batch {
+                Batch::Transaction(mgs) => self.db.down_migration_transaction(mgs).await?,
+                Batch::NoTransaction(mg) => self.db.down_migration_no_transaction(mg).await?,
+            }
+        }
+
+        Ok(summaries)
+    }
+}
+
+fn check_target(migrations: &[Migration], version: u64) -> Result<()> {
+    if version != 0 && !migrations.iter().any(|mg| mg.version == version) {
+        return Err(MigratourError::UnknownVersion(version));
+    }
+
+    Ok(())
+}
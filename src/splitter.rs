@@ -0,0 +1,525 @@
+use crate::DatabaseType;
+
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub sql: String,
+    // line of the migration file the statement starts on
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub struct SplitError {
+    pub line: usize,
+    pub reason: String,
+}
+
+struct Lexer<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    database: &'a DatabaseType,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars().enumerate().all(|(i, c)| self.peek(i) == Some(c))
+    }
+
+    fn bump(&mut self, out: &mut String) -> Option<char> {
+        let c = self.peek(0)?;
+        if c == '\n' {
+            self.line += 1;
+        }
+        self.pos += 1;
+        out.push(c);
+        Some(c)
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.chars[..self.pos]
+            .iter()
+            .rev()
+            .take_while(|c| **c != '\n')
+            .all(|c| c.is_whitespace())
+    }
+
+    fn rest_of_line(&self) -> String {
+        self.chars[self.pos..]
+            .iter()
+            .take_while(|c| **c != '\n')
+            .collect()
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.peek(0) {
+            if c == '\n' {
+                break;
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn line_comment(&mut self, out: &mut String) {
+        while let Some(c) = self.peek(0) {
+            if c == '\n' {
+                break;
+            }
+            self.bump(out);
+        }
+    }
+
+    fn block_comment(&mut self, out: &mut String) -> Result<(), SplitError> {
+        let start_line = self.line;
+        // postgres allows nested block comments, mysql and sqlite do not
+        let nested = matches!(self.database, DatabaseType::Postgres);
+        let mut depth = 0;
+
+        loop {
+            if self.starts_with("/*") && (depth == 0 || nested) {
+                self.bump(out);
+                self.bump(out);
+                depth += 1;
+            } else if self.starts_with("*/") {
+                self.bump(out);
+                self.bump(out);
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(());
+                }
+            } else if self.bump(out).is_none() {
+                return Err(SplitError {
+                    line: start_line,
+                    reason: "unterminated block comment".to_string(),
+                });
+            }
+        }
+    }
+
+    fn quoted(
+        &mut self,
+        quote: char,
+        backslash_escapes: bool,
+        out: &mut String,
+    ) -> Result<(), SplitError> {
+        let start_line = self.line;
+        self.bump(out);
+
+        loop {
+            match self.bump(out) {
+                None => {
+                    return Err(SplitError {
+                        line: start_line,
+                        reason: format!("unterminated quoted text {}", quote),
+                    });
+                }
+                Some('\\') if backslash_escapes => {
+                    self.bump(out);
+                }
+                Some(c) if c == quote => {
+                    // a doubled quote is an escaped quote
+                    if self.peek(0) == Some(quote) {
+                        self.bump(out);
+                    } else {
+                        return Ok(());
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    // returns the tag ("$$" or "$body$") when a postgres dollar quote starts here
+    fn dollar_tag(&self) -> Option<String> {
+        let mut tag = String::from("$");
+        let mut i = 1;
+        loop {
+            let c = self.peek(i)?;
+            if c == '$' {
+                tag.push('$');
+                return Some(tag);
+            }
+            let valid = if i == 1 {
+                c.is_alphabetic() || c == '_'
+            } else {
+                c.is_alphanumeric() || c == '_'
+            };
+            if !valid {
+                return None;
+            }
+            tag.push(c);
+            i += 1;
+        }
+    }
+
+    fn dollar_quoted(&mut self, tag: &str, out: &mut String) -> Result<(), SplitError> {
+        let start_line = self.line;
+        for _ in 0..tag.chars().count() {
+            self.bump(out);
+        }
+
+        loop {
+            if self.starts_with(tag) {
+                for _ in 0..tag.chars().count() {
+                    self.bump(out);
+                }
+                return Ok(());
+            }
+            if self.bump(out).is_none() {
+                return Err(SplitError {
+                    line: start_line,
+                    reason: format!("unterminated dollar quoted string {}", tag),
+                });
+            }
+        }
+    }
+
+    fn word(&mut self, out: &mut String) -> String {
+        let mut word = String::new();
+        while let Some(c) = self.peek(0) {
+            if !(c.is_alphanumeric() || c == '_') {
+                break;
+            }
+            self.bump(out);
+            word.push(c);
+        }
+        word.to_uppercase()
+    }
+
+    fn peek_word(&self) -> String {
+        self.chars[self.pos..]
+            .iter()
+            .skip_while(|c| c.is_whitespace())
+            .take_while(|c| c.is_alphanumeric() || **c == '_')
+            .collect::<String>()
+            .to_uppercase()
+    }
+
+    fn skip_whitespace(&mut self, out: &mut String) {
+        while let Some(c) = self.peek(0) {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.bump(out);
+        }
+    }
+}
+
+// splits a migration file into statements, honouring string literals, quoted identifiers,
+// comments, postgres dollar quotes, BEGIN ... END bodies of triggers and routines and
+// mysql DELIMITER directives
+pub fn split_statements(sql: &str, database: &DatabaseType) -> Result<Vec<Statement>, SplitError> {
+    let mut lx = Lexer {
+        chars: sql.chars().collect(),
+        pos: 0,
+        line: 1,
+        database,
+    };
+
+    let is_mysql = matches!(database, DatabaseType::MySql);
+    let is_postgres = matches!(database, DatabaseType::Postgres);
+
+    let mut statements: Vec<Statement> = Vec::new();
+    let mut delimiter = String::from(";");
+
+    let mut current = String::new();
+    let mut start_line: Option<usize> = None;
+    let mut first_word: Option<String> = None;
+    // BEGIN only opens a block in the body of a trigger or routine, elsewhere it can be a name
+    let mut routine = false;
+    let mut depth: usize = 0;
+    let mut parens: usize = 0;
+
+    while let Some(c) = lx.peek(0) {
+        if is_mysql && start_line.is_none() && lx.at_line_start() {
+            let line = lx.rest_of_line();
+            let mut parts = line.split_whitespace();
+            if parts
+                .next()
+                .map(|w| w.eq_ignore_ascii_case("delimiter"))
+                .unwrap_or(false)
+            {
+                match parts.next() {
+                    Some(d) => delimiter = d.to_string(),
+                    None => {
+                        return Err(SplitError {
+                            line: lx.line,
+                            reason: "DELIMITER without a value".to_string(),
+                        });
+                    }
+                }
+                lx.skip_line();
+                current.clear();
+                continue;
+            }
+        }
+
+        let splits_here =
+            ((depth == 0 && parens == 0) || delimiter != ";") && lx.starts_with(&delimiter);
+        if splits_here {
+            lx.pos += delimiter.chars().count();
+            if let Some(line) = start_line {
+                statements.push(Statement {
+                    sql: current.trim().to_string(),
+                    line,
+                });
+            }
+            current.clear();
+            start_line = None;
+            first_word = None;
+            routine = false;
+            depth = 0;
+            parens = 0;
+            continue;
+        }
+
+        if lx.starts_with("--") || (is_mysql && c == '#') {
+            lx.line_comment(&mut current);
+            continue;
+        }
+
+        if lx.starts_with("/*") {
+            lx.block_comment(&mut current)?;
+            continue;
+        }
+
+        if c.is_whitespace() {
+            lx.bump(&mut current);
+            continue;
+        }
+
+        if start_line.is_none() {
+            start_line = Some(lx.line);
+        }
+
+        match c {
+            '\'' => {
+                // mysql strings and postgres E'' strings use backslash escapes
+                let escape_string = is_postgres
+                    && matches!(current.chars().last(), Some('E') | Some('e'))
+                    && !current
+                        .chars()
+                        .rev()
+                        .nth(1)
+                        .map(|p| p.is_alphanumeric() || p == '_')
+                        .unwrap_or(false);
+                lx.quoted('\'', is_mysql || escape_string, &mut current)?;
+            }
+            '"' => lx.quoted('"', is_mysql, &mut current)?,
+            '`' => lx.quoted('`', false, &mut current)?,
+            '$' if is_postgres => match lx.dollar_tag() {
+                Some(tag) => lx.dollar_quoted(&tag, &mut current)?,
+                None => {
+                    lx.bump(&mut current);
+                }
+            },
+            c if c.is_alphabetic() || c == '_' => {
+                let word = lx.word(&mut current);
+                if first_word.is_none() {
+                    first_word = Some(word.clone());
+                }
+
+                match word.as_str() {
+                    "TRIGGER" | "FUNCTION" | "PROCEDURE" | "EVENT"
+                        if first_word.as_deref() == Some("CREATE") && depth == 0 =>
+                    {
+                        routine = true
+                    }
+                    "BEGIN" if routine && parens == 0 => depth += 1,
+                    "CASE" => depth += 1,
+                    "END" => match lx.peek_word().as_str() {
+                        "IF" | "LOOP" | "WHILE" | "REPEAT" => {
+                            lx.skip_whitespace(&mut current);
+                            lx.word(&mut current);
+                        }
+                        "CASE" => {
+                            lx.skip_whitespace(&mut current);
+                            lx.word(&mut current);
+                            depth = depth.saturating_sub(1);
+                        }
+                        _ => depth = depth.saturating_sub(1),
+                    },
+                    _ => {}
+                }
+            }
+            c if c.is_alphanumeric() => {
+                lx.word(&mut current);
+            }
+            '(' => {
+                parens += 1;
+                lx.bump(&mut current);
+            }
+            ')' => {
+                parens = parens.saturating_sub(1);
+                lx.bump(&mut current);
+            }
+            _ => {
+                lx.bump(&mut current);
+            }
+        }
+    }
+
+    if let Some(line) = start_line {
+        statements.push(Statement {
+            sql: current.trim().to_string(),
+            line,
+        });
+    }
+
+    Ok(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(sql: &str, database: DatabaseType) -> Vec<String> {
+        split_statements(sql, &database)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.sql)
+            .collect()
+    }
+
+    #[test]
+    fn splits_on_semicolons_with_lines() {
+        let statements = split_statements(
+            "CREATE TABLE a(id int);\n\n-- b\nCREATE TABLE b(id int);",
+            &DatabaseType::Sqlite,
+        )
+        .unwrap();
+
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].sql, "CREATE TABLE a(id int)");
+        assert_eq!(statements[0].line, 1);
+        assert_eq!(statements[1].sql, "-- b\nCREATE TABLE b(id int)");
+        assert_eq!(statements[1].line, 4);
+    }
+
+    #[test]
+    fn ignores_semicolons_in_strings_and_identifiers() {
+        let sql = "INSERT INTO t VALUES ('a;b', 'it''s;');\nSELECT \"x;y\" FROM t;";
+
+        assert_eq!(
+            split(sql, DatabaseType::Postgres),
+            vec![
+                "INSERT INTO t VALUES ('a;b', 'it''s;')",
+                "SELECT \"x;y\" FROM t"
+            ]
+        );
+    }
+
+    #[test]
+    fn dollar_quotes() {
+        let sql = "CREATE FUNCTION f() RETURNS int AS $body$\nBEGIN\n  RETURN 1;\nEND;\n$body$ LANGUAGE plpgsql;\nDO $$ BEGIN PERFORM 1; END $$;\nSELECT $1;";
+
+        let statements = split(sql, DatabaseType::Postgres);
+        assert_eq!(statements.len(), 3);
+        assert!(statements[0].ends_with("$body$ LANGUAGE plpgsql"));
+        assert_eq!(statements[1], "DO $$ BEGIN PERFORM 1; END $$");
+        assert_eq!(statements[2], "SELECT $1");
+    }
+
+    #[test]
+    fn escape_strings() {
+        let sql = "SELECT E'a\\';b';\nSELECT 'a\\';\nSELECT note'x;y' FROM t;";
+
+        assert_eq!(
+            split(sql, DatabaseType::Postgres),
+            vec![
+                "SELECT E'a\\';b'",
+                "SELECT 'a\\'",
+                "SELECT note'x;y' FROM t"
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_block_comments_on_postgres_only() {
+        let sql = "/* a /* b; */ c; */ SELECT 1;";
+        assert_eq!(
+            split(sql, DatabaseType::Postgres),
+            vec![sql.trim_end_matches(';')]
+        );
+
+        let sql = "/* a /* b; */ SELECT 1;";
+        assert_eq!(
+            split(sql, DatabaseType::MySql),
+            vec![sql.trim_end_matches(';')]
+        );
+    }
+
+    #[test]
+    fn unterminated() {
+        let error = split_statements("SELECT 1;\nSELECT 'a;", &DatabaseType::Sqlite).unwrap_err();
+        assert_eq!(error.line, 2);
+
+        assert!(split_statements("/* a", &DatabaseType::Sqlite).is_err());
+        assert!(split_statements("SELECT $$ a", &DatabaseType::Postgres).is_err());
+    }
+
+    #[test]
+    fn mysql_delimiter() {
+        let sql = "DELIMITER //\nCREATE PROCEDURE p()\nBEGIN\n  SELECT 1;\n  SELECT 2;\nEND //\nDELIMITER ;\nCALL p();";
+
+        assert_eq!(
+            split(sql, DatabaseType::MySql),
+            vec![
+                "CREATE PROCEDURE p()\nBEGIN\n  SELECT 1;\n  SELECT 2;\nEND",
+                "CALL p()"
+            ]
+        );
+    }
+
+    #[test]
+    fn routine_bodies_without_delimiter() {
+        let sql = "CREATE PROCEDURE p()\nBEGIN\n  IF 1 THEN SELECT 1; END IF;\n  SELECT CASE WHEN 1 THEN 2 END;\nEND;\nCALL p();";
+
+        let statements = split(sql, DatabaseType::MySql);
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].ends_with("END"));
+        assert_eq!(statements[1], "CALL p()");
+    }
+
+    #[test]
+    fn trigger_bodies() {
+        let sql = "CREATE TRIGGER t AFTER INSERT ON a\nBEGIN\n  INSERT INTO b VALUES (1);\n  UPDATE c SET n = n + 1;\nEND;\nSELECT 1;";
+
+        let statements = split(sql, DatabaseType::Sqlite);
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].starts_with("CREATE TRIGGER"));
+        assert!(statements[0].ends_with("END"));
+        assert_eq!(statements[1], "SELECT 1");
+    }
+
+    #[test]
+    fn begin_outside_of_routines() {
+        let sql = "CREATE TABLE t(begin int);\nCREATE TABLE u(id int);\nBEGIN;\nCOMMIT;";
+
+        assert_eq!(
+            split(sql, DatabaseType::Sqlite),
+            vec![
+                "CREATE TABLE t(begin int)",
+                "CREATE TABLE u(id int)",
+                "BEGIN",
+                "COMMIT"
+            ]
+        );
+    }
+
+    #[test]
+    fn parentheses() {
+        let sql = "CREATE RULE r AS ON INSERT TO t DO INSTEAD (INSERT INTO a VALUES (1); INSERT INTO b VALUES (2));\nSELECT 1;";
+
+        assert_eq!(
+            split(sql, DatabaseType::Postgres),
+            vec![
+                "CREATE RULE r AS ON INSERT TO t DO INSTEAD (INSERT INTO a VALUES (1); INSERT INTO b VALUES (2))",
+                "SELECT 1"
+            ]
+        );
+    }
+}
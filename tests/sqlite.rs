@@ -0,0 +1,387 @@
+use migratour::{
+    EmbeddedMigration, MigrationState, Migrator, MigratourError, Target, TransactionMode,
+};
+use sqlx::SqlitePool;
+
+static MIGRATIONS: &[EmbeddedMigration] = &[
+    EmbeddedMigration {
+        file_name: "0001_users.up.sql",
+        sql: "CREATE TABLE users (id integer primary key, name text not null);",
+    },
+    EmbeddedMigration {
+        file_name: "0001_users.down.sql",
+        sql: "DROP TABLE users;",
+    },
+    EmbeddedMigration {
+        file_name: "0002_posts.up.sql",
+        sql: "CREATE TABLE posts (id integer primary key, user_id integer references users(id));
+INSERT INTO users (name) VALUES ('admin');",
+    },
+    EmbeddedMigration {
+        file_name: "0002_posts.down.sql",
+        sql: "DROP TABLE posts;\nDELETE FROM users;",
+    },
+];
+
+// the second statement of the no-transaction migration fails after the first one ran
+static BROKEN_MIGRATIONS: &[EmbeddedMigration] = &[
+    EmbeddedMigration {
+        file_name: "0001_users.up.sql",
+        sql: "CREATE TABLE users (id integer primary key, name text not null);",
+    },
+    EmbeddedMigration {
+        file_name: "0001_users.down.sql",
+        sql: "DROP TABLE users;",
+    },
+    EmbeddedMigration {
+        file_name: "0002_tags.up.sql",
+        sql: "-- migratour:no-transaction
+CREATE TABLE tags (id integer primary key);
+INSERT INTO missing VALUES (1);",
+    },
+    EmbeddedMigration {
+        file_name: "0002_tags.down.sql",
+        sql: "DROP TABLE tags;",
+    },
+];
+
+async fn pool() -> SqlitePool {
+    SqlitePool::connect("sqlite::memory:").await.unwrap()
+}
+
+async fn migrator(pool: &SqlitePool, migrations: &'static [EmbeddedMigration]) -> Migrator {
+    Migrator::builder()
+        .embedded_migrations(migrations)
+        .pool(pool.clone())
+        .build()
+        .await
+        .unwrap()
+}
+
+async fn exists(pool: &SqlitePool, kind: &str, name: &str) -> bool {
+    let count: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = ? AND name = ?")
+            .bind(kind)
+            .bind(name)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+
+    count > 0
+}
+
+async fn table_exists(pool: &SqlitePool, table: &str) -> bool {
+    exists(pool, "table", table).await
+}
+
+fn states(statuses: &[migratour::MigrationStatus]) -> Vec<(String, MigrationState)> {
+    statuses
+        .iter()
+        .map(|st| (st.name.clone(), st.state.clone()))
+        .collect()
+}
+
+#[tokio::test]
+async fn up_and_down() {
+    let pool = pool().await;
+    let migrator = migrator(&pool, MIGRATIONS).await;
+    assert!(exists(&pool, "index", "db_migrations_name_idx").await);
+
+    assert_eq!(
+        states(&migrator.status().await.unwrap()),
+        vec![
+            ("users".to_string(), MigrationState::Pending),
+            ("posts".to_string(), MigrationState::Pending),
+        ]
+    );
+
+    let applied = migrator.up(None).await.unwrap();
+    assert_eq!(applied.len(), 2);
+    assert!(table_exists(&pool, "posts").await);
+    assert_eq!(migrator.count().await.unwrap(), 2);
+    assert_eq!(migrator.last().await.unwrap().as_deref(), Some("posts"));
+    assert_eq!(migrator.verify().await.unwrap(), 2);
+
+    let reverted = migrator.down(1).await.unwrap();
+    assert_eq!(reverted[0].name, "posts");
+    assert!(!table_exists(&pool, "posts").await);
+    assert!(table_exists(&pool, "users").await);
+    assert_eq!(
+        states(&migrator.status().await.unwrap()),
+        vec![
+            ("users".to_string(), MigrationState::Applied),
+            ("posts".to_string(), MigrationState::Pending),
+        ]
+    );
+
+    assert!(matches!(
+        migrator.down(2).await,
+        Err(MigratourError::NotEnoughMigrations { .. })
+    ));
+    migrator.down(1).await.unwrap();
+    assert_eq!(migrator.count().await.unwrap(), 0);
+    assert_eq!(migrator.last().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn transaction_modes() {
+    for mode in [
+        TransactionMode::All,
+        TransactionMode::PerMigration,
+        TransactionMode::None,
+    ] {
+        let pool = pool().await;
+        let migrator = Migrator::builder()
+            .embedded_migrations(MIGRATIONS)
+            .pool(pool.clone())
+            .transaction_mode(mode)
+            .build()
+            .await
+            .unwrap();
+
+        migrator.up_to(2).await.unwrap();
+        assert_eq!(migrator.count().await.unwrap(), 2);
+        migrator.down_to(0).await.unwrap();
+        assert!(!table_exists(&pool, "users").await);
+    }
+}
+
+#[tokio::test]
+async fn dry_run_changes_nothing() {
+    let pool = pool().await;
+    let migrator = migrator(&pool, MIGRATIONS).await;
+
+    let plan = migrator.up_dry_run(Target::All).await.unwrap();
+    assert_eq!(plan.len(), 2);
+    assert_eq!(plan[1].statements.len(), 2);
+
+    assert!(!table_exists(&pool, "users").await);
+    assert_eq!(migrator.count().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn dry_run_stops_at_no_transaction_migrations() {
+    static MIGRATIONS: &[EmbeddedMigration] = &[
+        EmbeddedMigration {
+            file_name: "0001_users.up.sql",
+            sql: "CREATE TABLE users (id integer primary key);",
+        },
+        EmbeddedMigration {
+            file_name: "0002_tags.up.sql",
+            sql: "-- migratour:no-transaction\nCREATE TABLE tags (id integer primary key);",
+        },
+        EmbeddedMigration {
+            file_name: "0003_tag_users.up.sql",
+            sql: "INSERT INTO tags (id) VALUES (1);",
+        },
+    ];
+
+    let pool = pool().await;
+    let migrator = migrator(&pool, MIGRATIONS).await;
+
+    let plan = migrator.up_dry_run(Target::All).await.unwrap();
+    let runs: Vec<(bool, bool)> = plan.iter().map(|mg| (mg.no_transaction, mg.run)).collect();
+    assert_eq!(runs, vec![(false, true), (true, false), (false, false)]);
+    assert!(!table_exists(&pool, "users").await);
+
+    migrator.up(None).await.unwrap();
+    assert_eq!(migrator.count().await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn history_records_reverts() {
+    let pool = pool().await;
+    let migrator = migrator(&pool, MIGRATIONS).await;
+
+    migrator.up(None).await.unwrap();
+    migrator.down(1).await.unwrap();
+    migrator.up(None).await.unwrap();
+
+    let history = migrator.history().await.unwrap();
+    let events: Vec<(&str, u64, Option<u64>)> = history
+        .iter()
+        .map(|entry| (entry.name.as_str(), entry.applied_seq, entry.reverted_seq))
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            ("users", 1, None),
+            ("posts", 2, Some(3)),
+            ("posts", 4, None)
+        ]
+    );
+    assert!(history.iter().all(|entry| !entry.dirty && !entry.discarded));
+    assert_eq!(history[1].version_label.as_deref(), Some("0002"));
+    assert!(history[1].reverted_by.is_some());
+}
+
+#[tokio::test]
+async fn goto_and_allow_down() {
+    let pool = pool().await;
+    let migrator = migrator(&pool, MIGRATIONS).await;
+
+    migrator.goto(2).await.unwrap();
+    migrator.goto(1).await.unwrap();
+    assert_eq!(migrator.last().await.unwrap().as_deref(), Some("users"));
+    assert!(matches!(
+        migrator.goto(3).await,
+        Err(MigratourError::UnknownVersion(3))
+    ));
+
+    let migrator = Migrator::builder()
+        .embedded_migrations(MIGRATIONS)
+        .pool(pool.clone())
+        .allow_down(false)
+        .build()
+        .await
+        .unwrap();
+    assert!(matches!(
+        migrator.down(1).await,
+        Err(MigratourError::DownNotAllowed)
+    ));
+}
+
+#[tokio::test]
+async fn repair_dirty_migration() {
+    let pool = pool().await;
+    let migrator = migrator(&pool, BROKEN_MIGRATIONS).await;
+
+    assert!(matches!(
+        migrator.up(None).await,
+        Err(MigratourError::StatementFailed { index: 2, .. })
+    ));
+    assert!(table_exists(&pool, "tags").await);
+    assert_eq!(
+        states(&migrator.status().await.unwrap()),
+        vec![
+            ("users".to_string(), MigrationState::Applied),
+            ("tags".to_string(), MigrationState::Dirty),
+        ]
+    );
+    assert!(matches!(
+        migrator.up(None).await,
+        Err(MigratourError::Dirty { .. })
+    ));
+    assert!(matches!(
+        migrator.repair(1, false).await,
+        Err(MigratourError::InvalidArgument(_))
+    ));
+
+    // cleaned up by hand, then marked as not applied so that it is pending again
+    sqlx::query("DROP TABLE tags").execute(&pool).await.unwrap();
+    migrator.repair(2, false).await.unwrap();
+    assert_eq!(
+        states(&migrator.status().await.unwrap()),
+        vec![
+            ("users".to_string(), MigrationState::Applied),
+            ("tags".to_string(), MigrationState::Pending),
+        ]
+    );
+
+    let history = migrator.history().await.unwrap();
+    assert!(history[1].discarded);
+    assert!(!history[1].dirty);
+    assert_eq!(history[1].reverted_seq, Some(3));
+
+    // failing again, and this time finished by hand and marked as applied
+    migrator.up(None).await.unwrap_err();
+    sqlx::query("CREATE TABLE missing (id integer)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    migrator.repair(2, true).await.unwrap();
+    assert_eq!(migrator.count().await.unwrap(), 2);
+    assert!(migrator
+        .status()
+        .await
+        .unwrap()
+        .iter()
+        .all(|st| st.state == MigrationState::Applied));
+}
+
+#[tokio::test]
+async fn modified_migration() {
+    static MODIFIED: &[EmbeddedMigration] = &[
+        EmbeddedMigration {
+            file_name: "0001_users.up.sql",
+            sql: "CREATE TABLE users (id integer primary key, email text);",
+        },
+        EmbeddedMigration {
+            file_name: "0001_users.down.sql",
+            sql: "DROP TABLE users;",
+        },
+    ];
+
+    let pool = pool().await;
+    migrator(&pool, MIGRATIONS).await.up(Some(1)).await.unwrap();
+
+    let migrator = migrator(&pool, MODIFIED).await;
+    assert_eq!(
+        states(&migrator.status().await.unwrap()),
+        vec![("users".to_string(), MigrationState::Modified)]
+    );
+    match migrator.verify().await {
+        Err(MigratourError::ChecksumMismatch { migrations }) => {
+            assert_eq!(migrations, vec!["0001_users".to_string()])
+        }
+        result => panic!("expected a checksum mismatch, got {:?}", result),
+    }
+}
+
+// a table created by the first migratour, before the layout was versioned
+#[tokio::test]
+async fn upgrades_the_first_layout() {
+    let pool = pool().await;
+    sqlx::query(
+        "CREATE TABLE db_migrations(
+            id integer primary key autoincrement,
+            name text unique,
+            valid boolean,
+            created_at timestamp not null default current_timestamp,
+            deleted_at timestamp
+        )",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query("CREATE TABLE users (id integer primary key, name text not null)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        "INSERT INTO db_migrations (name, valid, created_at)
+        VALUES ('users', true, '2023-08-01 10:00:00')",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let migrator = migrator(&pool, MIGRATIONS).await;
+    assert!(exists(&pool, "index", "db_migrations_name_idx").await);
+    assert_eq!(
+        states(&migrator.status().await.unwrap()),
+        vec![
+            ("users".to_string(), MigrationState::Applied),
+            ("posts".to_string(), MigrationState::Pending),
+        ]
+    );
+
+    migrator.up(None).await.unwrap();
+    migrator.down(2).await.unwrap();
+    migrator.up(Some(1)).await.unwrap();
+
+    let history = migrator.history().await.unwrap();
+    let events: Vec<(&str, u64, Option<u64>)> = history
+        .iter()
+        .map(|entry| (entry.name.as_str(), entry.applied_seq, entry.reverted_seq))
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            ("users", 1, Some(4)),
+            ("posts", 2, Some(3)),
+            ("users", 5, None)
+        ]
+    );
+    assert!(history[0].applied_by.is_none());
+}